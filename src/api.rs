@@ -0,0 +1,648 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::audit_obj::AuditJson;
+use crate::cookie::SESSION_COOKIE;
+use crate::cookie::{clear_session_cookie, session_cookie};
+use crate::crypto_helpers::secret_matches;
+use crate::csrf::{self, CSRF_HEADER};
+use crate::db::audit;
+use crate::db::post::*;
+use crate::db::store::Backend;
+use crate::db::user::*;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::mail::send_verification;
+use crate::post::{
+    filter_content, flag_post, take_post_rate_limit, validate_reply, POSTING_TOO_QUICKLY,
+};
+use crate::post_obj::PostJson;
+use crate::render::{audit_filter_from_query, ViewOptions, AUDIT_PAGE_SIZE};
+use crate::user_obj;
+
+#[derive(Deserialize)]
+struct LoginBody {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RegisterBody {
+    email: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct MigrateBody {
+    phase: String,
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReplyBody {
+    title: String,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct SessionJson {
+    session_id: String,
+}
+
+#[derive(Serialize)]
+struct MessageJson<'a> {
+    message: &'a str,
+}
+
+#[derive(Serialize)]
+struct RepliesJson {
+    replies: Vec<PostJson>,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuditLogJson {
+    entries: Vec<AuditJson>,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MigrateJson {
+    migrated: usize,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorJson<'a> {
+    error: &'a str,
+    status: u16,
+}
+
+/*
+ * Records moved per migration request, each one takes several kv operations
+ * */
+const MIGRATION_PAGE_SIZE: u64 = 50;
+
+pub fn api_error(message: &str, status: u16) -> Result<HttpResponse> {
+    Ok(HttpResponse::from_json(&ErrorJson {
+        error: message,
+        status,
+    })?
+    .with_status(status))
+}
+
+/*
+ * Json api, served under /api/v1/
+ *
+ * GET  posts/{id}          the post
+ * GET  posts/{id}/replies  a page of direct replies, ?sort= ?limit= and ?cursor= as for pages
+ * POST posts/{id}/replies  reply to the post with {title, content}
+ * POST login               {email, password}
+ * POST register            {email, username, password}, the account is verified by email
+ * POST logout
+ * POST migrate             {phase, cursor}, needs the MIGRATION_KEY secret as X-Migration-Key
+ * GET  audit               a page of the audit log for admins, ?actor= (a username) ?action=
+ *                          ?target= and ?cursor=
+ *
+ * The root post has an empty id, e.g. posts/ and posts//replies. Clients authenticate with the
+ * session id as a bearer token, posts authenticated by the session cookie instead also need the
+ * page's csrf token as X-CSRF-Token
+ * */
+pub async fn handle_api_request<E: Backend, S: AsRef<str>>(
+    req: HttpRequest,
+    env: &E,
+    user: Option<user_obj::User>,
+    session_id: Option<S>,
+) -> Result<HttpResponse> {
+    let route = match req.path.strip_prefix("/api/v1/") {
+        Some(route) => route,
+        None => return api_error("Not found", 404),
+    };
+
+    // The browser sends the cookie whichever site made the request, a bearer token has to be added
+    // by the client itself
+    if req.method == Method::Post && req.cookie(SESSION_COOKIE).is_some() {
+        if let Some(session_id) = &session_id {
+            if !csrf::verify(env, session_id.as_ref(), req.header(CSRF_HEADER))? {
+                return api_error("Error, the X-CSRF-Token header is missing or invalid", 403);
+            }
+        }
+    }
+
+    match route {
+        "login" | "register" | "logout" | "migrate" if req.method != Method::Post => {
+            api_error("Method not allowed", 405)
+        }
+        "login" => match serde_json::from_str::<LoginBody>(&req.body) {
+            Ok(body) => login(env, body, &req.client()).await,
+            Err(_) => api_error("Bad request, email and password must be present", 400),
+        },
+        "register" => match serde_json::from_str::<RegisterBody>(&req.body) {
+            Ok(body) => register(env, body).await,
+            Err(_) => api_error(
+                "Bad request, email, username and password must be present",
+                400,
+            ),
+        },
+        "logout" => match (user, session_id) {
+            (Some(_), Some(session_id)) => {
+                delete_session(env, session_id).await?;
+                Ok(HttpResponse::empty()?
+                    .with_status(204)
+                    .with_header("Set-Cookie", clear_session_cookie()))
+            }
+            _ => api_error("Error, User is not logged in!", 401),
+        },
+        "migrate" => match serde_json::from_str::<MigrateBody>(&req.body) {
+            Ok(body) => migrate(env, body, req.header("X-Migration-Key")).await,
+            Err(_) => api_error("Bad request, phase must be present", 400),
+        },
+        "audit" if req.method != Method::Get => api_error("Method not allowed", 405),
+        "audit" => match user {
+            Some(user) if user.account.role == user_obj::Role::Admin => {
+                audit_log(env, &req.query).await
+            }
+            Some(_) => api_error("Error: Insufficient permissions", 403),
+            None => api_error("Error, User is not logged in!", 401),
+        },
+        _ => match route.strip_prefix("posts/") {
+            Some(post_route) => {
+                let (post_id, sub_route) = match post_route.split_once('/') {
+                    Some((post_id, sub_route)) => (post_id, Some(sub_route)),
+                    None => (post_route, None),
+                };
+                match (&req.method, sub_route) {
+                    (Method::Get, None) => get_post(env, post_id).await,
+                    (Method::Get, Some("replies")) => {
+                        list_replies(env, post_id, &ViewOptions::from_query(&req.query)).await
+                    }
+                    (Method::Post, Some("replies")) => {
+                        match serde_json::from_str::<ReplyBody>(&req.body) {
+                            Ok(body) => reply(env, post_id, body, user, &req.client()).await,
+                            Err(_) => api_error(
+                                "Bad request, title and content must both be present.",
+                                400,
+                            ),
+                        }
+                    }
+                    (_, None) | (_, Some("replies")) => api_error("Method not allowed", 405),
+                    _ => api_error("Not found", 404),
+                }
+            }
+            None => api_error("Not found", 404),
+        },
+    }
+}
+
+async fn login<E: Backend>(
+    env: &E,
+    body: LoginBody,
+    client: &user_obj::Client,
+) -> Result<HttpResponse> {
+    match create_session(env, &body.email, &body.password, client).await? {
+        Ok(session_id) => Ok(HttpResponse::from_json(&SessionJson {
+            session_id: session_id.to_string(),
+        })?
+        .with_header(
+            "Set-Cookie",
+            session_cookie(&session_id, session_expiry(env)?),
+        )),
+        Err(error) => {
+            let response = api_error(error.message(), error.status())?;
+            Ok(match error {
+                LoginError::TooManyAttempts(retry_after) => {
+                    response.with_header("Retry-After", retry_after.to_string())
+                }
+                _ => response,
+            })
+        }
+    }
+}
+
+/*
+ * Accounts have to be verified through the emailed link before they can log in
+ * */
+async fn register<E: Backend>(env: &E, body: RegisterBody) -> Result<HttpResponse> {
+    match create_user(env, &body.email, &body.username, &body.password).await? {
+        Ok(token) => {
+            send_verification(env, &body.email, &token).await?;
+            Ok(HttpResponse::from_json(&MessageJson {
+                message: "Check your email to verify your account",
+            })?
+            .with_status(202))
+        }
+        Err(error) => api_error(error.message(), error.status()),
+    }
+}
+
+/*
+ * Move records from before user ids a page at a time, the users phase has to finish before the
+ * posts phase is run. Each response has the cursor to send with the next request, until it is null
+ * */
+async fn migrate<E: Backend>(
+    env: &E,
+    body: MigrateBody,
+    key: Option<&str>,
+) -> Result<HttpResponse> {
+    // Only available while a key is configured
+    let secret = match env.var("MIGRATION_KEY") {
+        Ok(secret) => secret,
+        Err(_) => return api_error("Not found", 404),
+    };
+    if !matches!(key, Some(key) if secret_matches(&secret, key)) {
+        return api_error("Error, migration key is missing or incorrect", 403);
+    }
+
+    let cursor = body.cursor.as_deref();
+    let (migrated, cursor) = match body.phase.as_str() {
+        "users" => migrate_legacy_users(env, cursor, MIGRATION_PAGE_SIZE).await?,
+        "posts" => migrate_post_users(env, cursor, MIGRATION_PAGE_SIZE).await?,
+        _ => return api_error("Bad request, phase must be users or posts", 400),
+    };
+    HttpResponse::from_json(&MigrateJson { migrated, cursor })
+}
+
+async fn audit_log<E: Backend>(
+    env: &E,
+    query: &std::collections::HashMap<String, String>,
+) -> Result<HttpResponse> {
+    let cursor = query
+        .get("cursor")
+        .map(|cursor| cursor.as_str())
+        .filter(|cursor| !cursor.is_empty());
+    let (entries, cursor) = match audit_filter_from_query(env, query).await? {
+        Some(filter) => audit::list(env, &filter, cursor, AUDIT_PAGE_SIZE).await?,
+        None => (Vec::new(), None),
+    };
+    HttpResponse::from_json(&AuditLogJson {
+        entries: entries.iter().map(AuditJson::from).collect(),
+        cursor,
+    })
+}
+
+async fn get_post<E: Backend>(env: &E, post_id: &str) -> Result<HttpResponse> {
+    match get_content(env, post_id).await? {
+        Some(post) => HttpResponse::from_json(&PostJson::from(&post)),
+        None => api_error("Post not found", 404),
+    }
+}
+
+async fn list_replies<E: Backend>(
+    env: &E,
+    post_id: &str,
+    options: &ViewOptions,
+) -> Result<HttpResponse> {
+    if get_content(env, post_id).await?.is_none() {
+        return api_error("Post not found", 404);
+    }
+    let (replies, cursor) = get_replies_page(
+        env,
+        post_id,
+        options.sort,
+        options.cursor.as_deref(),
+        options.limit,
+    )
+    .await?;
+    HttpResponse::from_json(&RepliesJson {
+        replies: replies.iter().map(PostJson::from).collect(),
+        cursor,
+    })
+}
+
+async fn reply<E: Backend>(
+    env: &E,
+    post_id: &str,
+    body: ReplyBody,
+    user: Option<user_obj::User>,
+    client: &user_obj::Client,
+) -> Result<HttpResponse> {
+    let user = match user {
+        None => return api_error("Error, User is not logged in!", 401),
+        Some(user) => user,
+    };
+
+    if let Some(error) = validate_reply(env, post_id, &body.title).await? {
+        return api_error(error.message(), error.status());
+    }
+    let filtered = match filter_content(env, &body.content).await? {
+        Ok(filtered) => filtered,
+        Err(message) => return api_error(&message, 400),
+    };
+    if let Some(retry_after) =
+        take_post_rate_limit(env, &user.user_id, client.ip.as_deref()).await?
+    {
+        return Ok(api_error(POSTING_TOO_QUICKLY, 429)?
+            .with_header("Retry-After", retry_after.to_string()));
+    }
+
+    let fulltitle = format!("{}{}", post_id, body.title);
+    post_content(env, fulltitle.as_str(), filtered.content.as_str(), user).await?;
+    flag_post(env, &fulltitle, &filtered.flags).await?;
+
+    match get_content(env, fulltitle.as_str()).await? {
+        Some(post) => Ok(HttpResponse::from_json(&PostJson::from(&post))?
+            .with_status(201)
+            .with_header("Location", format!("/api/v1/posts/{}", fulltitle))),
+        None => api_error("An error has occured", 500),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::audit_obj::AuditAction;
+    use crate::csrf;
+    use crate::db::audit;
+    use crate::db::memory::MemoryBackend;
+    use crate::db::post::post_content;
+    use crate::db::store::Backend;
+    use crate::db::user::{get_session, verify_user};
+    use crate::harness::{emailed_token, register, session_from_cookie, TestRequest};
+    use futures::executor::block_on;
+    use serde_json::{json, Value};
+
+    fn setup() -> (MemoryBackend, String) {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        for (post_id, content) in &[("", "root"), ("a", "a <b>reply</b>"), ("ab", "")] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, content, user)).unwrap();
+        }
+        (env, session_id)
+    }
+
+    fn body(response: &crate::http::HttpResponse) -> Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn get_post() {
+        let (env, _) = setup();
+        let response = TestRequest::get("/api/v1/posts/a").send(&env);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(
+            body(&response),
+            json!({
+                "title": "a",
+                "author": "alice",
+                "content": "a <b>reply</b>",
+                "created_at": env.now(),
+                "updated_at": null,
+                "reply_count": 1,
+                "score": 0,
+                "deleted": false,
+                "removed": false
+            })
+        );
+
+        let response = TestRequest::get("/api/v1/posts/").send(&env);
+        assert_eq!(body(&response)["content"], "root");
+    }
+
+    #[test]
+    fn get_missing_post() {
+        let (env, _) = setup();
+        let response = TestRequest::get("/api/v1/posts/b").send(&env);
+        assert_eq!(response.status, 404);
+        assert_eq!(body(&response)["status"], 404);
+
+        let response = TestRequest::get("/api/v1/posts/b/replies").send(&env);
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn get_replies() {
+        let (env, _) = setup();
+        let response = TestRequest::get("/api/v1/posts//replies").send(&env);
+        assert_eq!(response.status, 200);
+        let titles = body(&response)["replies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|post| post["title"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["a"]);
+        assert!(body(&response)["cursor"].is_null());
+    }
+
+    #[test]
+    fn replies_are_paginated() {
+        let (env, session_id) = setup();
+        for post_id in &["b", "c"] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, "", user)).unwrap();
+        }
+
+        let response = TestRequest::get("/api/v1/posts//replies")
+            .query_value("limit", "2")
+            .send(&env);
+        let page = body(&response);
+        assert_eq!(page["replies"].as_array().unwrap().len(), 2);
+        let cursor = page["cursor"].as_str().unwrap();
+
+        let response = TestRequest::get("/api/v1/posts//replies")
+            .query_value("limit", "2")
+            .query_value("cursor", cursor)
+            .send(&env);
+        let page = body(&response);
+        assert_eq!(page["replies"][0]["title"], "c");
+        assert!(page["cursor"].is_null());
+    }
+
+    #[test]
+    fn unknown_routes() {
+        let (env, _) = setup();
+        let response = TestRequest::get("/api/v1/posts/a/children").send(&env);
+        assert_eq!(response.status, 404);
+        let response = TestRequest::get("/api/v2/posts/a").send(&env);
+        assert_eq!(response.status, 404);
+        let response = TestRequest::get("/api/v1/login").send(&env);
+        assert_eq!(response.status, 405);
+    }
+
+    #[test]
+    fn login_and_reply_with_bearer_token() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/api/v1/login")
+            .body(r#"{"email": "a@example.com", "password": "password"}"#)
+            .send(&env);
+        assert_eq!(response.status, 200);
+        let session_id = body(&response)["session_id"].as_str().unwrap().to_string();
+
+        let response = TestRequest::post("/api/v1/posts/a/replies")
+            .header("Authorization", &format!("Bearer {}", session_id))
+            .body(r#"{"title": "c", "content": "new"}"#)
+            .send(&env);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Location"), Some("/api/v1/posts/ac"));
+        assert_eq!(body(&response)["title"], "ac");
+    }
+
+    #[test]
+    fn login_wrong_password() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/api/v1/login")
+            .body(r#"{"email": "a@example.com", "password": "wrong"}"#)
+            .send(&env);
+        assert_eq!(response.status, 401);
+        let response = TestRequest::post("/api/v1/login")
+            .body(r#"{"email": "a@example.com"}"#)
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn register_and_logout() {
+        let (env, _) = setup();
+        let request = r#"{"email": "b@example.com", "username": "bob", "password": "password"}"#;
+        let response = TestRequest::post("/api/v1/register")
+            .body(request)
+            .send(&env);
+        assert_eq!(response.status, 202);
+
+        // Registering again before verifying sends a new link, e.g. if the first never arrived
+        let response = TestRequest::post("/api/v1/register")
+            .body(request)
+            .send(&env);
+        assert_eq!(response.status, 202);
+
+        let login = || {
+            TestRequest::post("/api/v1/login")
+                .body(r#"{"email": "b@example.com", "password": "password"}"#)
+                .send(&env)
+        };
+        assert_eq!(login().status, 403);
+        assert!(block_on(verify_user(&env, &emailed_token(&env)))
+            .unwrap()
+            .is_some());
+        let response = login();
+        assert_eq!(response.status, 200);
+        let session_id = body(&response)["session_id"].as_str().unwrap().to_string();
+        let response = TestRequest::post("/api/v1/register")
+            .body(request)
+            .send(&env);
+        assert_eq!(response.status, 409);
+
+        let response = TestRequest::post("/api/v1/logout")
+            .cookie("sessionId", &session_id)
+            .header("X-CSRF-Token", &csrf::token(&env, &session_id).unwrap())
+            .send(&env);
+        assert_eq!(response.status, 204);
+        assert_eq!(session_from_cookie(&response), Some(String::new()));
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_none());
+    }
+
+    #[test]
+    fn reply_validation_is_shared() {
+        let (env, session_id) = setup();
+        let reply = |title: &str| {
+            TestRequest::post("/api/v1/posts/a/replies")
+                .header("Authorization", &format!("Bearer {}", session_id))
+                .body(&json!({"title": title, "content": ""}).to_string())
+                .send(&env)
+        };
+        assert_eq!(reply("b").status, 409);
+        assert_eq!(reply("$").status, 400);
+        assert_eq!(reply("cd").status, 400);
+
+        let response = TestRequest::post("/api/v1/posts/a/replies")
+            .body(r#"{"title": "c", "content": ""}"#)
+            .send(&env);
+        assert_eq!(response.status, 401);
+    }
+
+    #[test]
+    fn cookie_sessions_need_the_csrf_token() {
+        let (env, session_id) = setup();
+        let reply = |title: &str, token: Option<&str>| {
+            let mut request = TestRequest::post("/api/v1/posts/a/replies")
+                .cookie("sessionId", &session_id)
+                .body(&json!({"title": title, "content": ""}).to_string());
+            if let Some(token) = token {
+                request = request.header("X-CSRF-Token", token);
+            }
+            request.send(&env)
+        };
+        assert_eq!(reply("c", None).status, 403);
+        assert_eq!(reply("c", Some("wrong")).status, 403);
+        assert!(block_on(crate::db::post::get_content(&env, "ac"))
+            .unwrap()
+            .is_none());
+        let token = csrf::token(&env, &session_id).unwrap();
+        assert_eq!(reply("c", Some(&token)).status, 201);
+    }
+
+    #[test]
+    fn migrate_needs_key() {
+        let (mut env, _) = setup();
+        let migrate = |env: &MemoryBackend, key: &str| {
+            TestRequest::post("/api/v1/migrate")
+                .header("X-Migration-Key", key)
+                .body(r#"{"phase": "users", "cursor": null}"#)
+                .send(env)
+        };
+        assert_eq!(migrate(&env, "").status, 404);
+
+        env.set_var("MIGRATION_KEY", "key");
+        assert_eq!(migrate(&env, "wrong").status, 403);
+        let response = migrate(&env, "key");
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), json!({"migrated": 0, "cursor": null}));
+
+        let response = TestRequest::post("/api/v1/migrate")
+            .header("X-Migration-Key", "key")
+            .body(r#"{"phase": "votes"}"#)
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn audit_log_is_for_admins() {
+        let (mut env, session_id) = setup();
+        env.set_var("ADMIN_EMAILS", "admin@example.com");
+        let admin_id = register(&env, "admin@example.com", "boss");
+        let admin = block_on(get_session(&env, &admin_id)).unwrap().unwrap();
+        block_on(audit::record(
+            &env,
+            &admin,
+            AuditAction::LockPost,
+            "a",
+            Some("flamewar"),
+        ))
+        .unwrap();
+        let audit_log = |session_id: &str, actor: &str| {
+            TestRequest::get("/api/v1/audit")
+                .query_value("actor", actor)
+                .header("Authorization", &format!("Bearer {}", session_id))
+                .send(&env)
+        };
+
+        assert_eq!(audit_log("", "").status, 401);
+        assert_eq!(audit_log(&session_id, "").status, 403);
+        let response = audit_log(&admin_id, "");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            body(&response),
+            json!({
+                "entries": [{
+                    "timestamp": env.now(),
+                    "actor": admin.user_id,
+                    "action": "lock",
+                    "role": null,
+                    "until": null,
+                    "target": "a",
+                    "reason": "flamewar",
+                }],
+                "cursor": null,
+            })
+        );
+        for actor in &["alice", "nobody"] {
+            let response = audit_log(&admin_id, actor);
+            assert_eq!(body(&response)["entries"], json!([]));
+        }
+        assert_eq!(
+            body(&audit_log(&admin_id, "boss"))["entries"][0]["target"],
+            "a"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::user_obj::Role;
+use serde::{Deserialize, Serialize};
+
+/*
+ * Privileged or destructive actions, each one is recorded in the audit log
+ * */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    RemovePost,
+    RestorePost,
+    LockPost,
+    UnlockPost,
+    /*
+     * Closing reports on a post without acting on it
+     * */
+    DismissReports,
+    ChangeRole(Role),
+    /*
+     * Until when, None for a permanent ban
+     * */
+    BanUser(Option<u64>),
+    UnbanUser,
+    /*
+     * Done by the post's author, unlike the other post actions
+     * */
+    EditPost,
+    DeletePost,
+    PurgePost,
+}
+
+impl AuditAction {
+    pub const NAMES: [&'static str; 11] = [
+        "remove", "restore", "lock", "unlock", "dismiss", "role", "ban", "unban", "edit", "delete",
+        "purge",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditAction::RemovePost => "remove",
+            AuditAction::RestorePost => "restore",
+            AuditAction::LockPost => "lock",
+            AuditAction::UnlockPost => "unlock",
+            AuditAction::DismissReports => "dismiss",
+            AuditAction::ChangeRole(_) => "role",
+            AuditAction::BanUser(_) => "ban",
+            AuditAction::UnbanUser => "unban",
+            AuditAction::EditPost => "edit",
+            AuditAction::DeletePost => "delete",
+            AuditAction::PurgePost => "purge",
+        }
+    }
+
+    /*
+     * Whether the target is a post id, otherwise it is a user id
+     * */
+    pub fn targets_post(&self) -> bool {
+        !matches!(
+            self,
+            AuditAction::ChangeRole(_) | AuditAction::BanUser(_) | AuditAction::UnbanUser
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    /*
+     * Id of the user who took the action
+     * */
+    pub actor: String,
+    pub action: AuditAction,
+    /*
+     * The post acted on, or the user id for actions on accounts
+     * */
+    pub target: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/*
+ * Stored as KV metadata on each entry so that the log can be filtered from the key listing alone.
+ * Entries written before this have none and are read in full instead
+ * */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditMetadata {
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+}
+
+impl From<&AuditEntry> for AuditMetadata {
+    fn from(entry: &AuditEntry) -> Self {
+        AuditMetadata {
+            actor: entry.actor.to_string(),
+            action: entry.action.name().to_string(),
+            target: entry.target.to_string(),
+        }
+    }
+}
+
+/*
+ * Narrows the log down to entries matching every field that is set
+ * */
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, metadata: &AuditMetadata) -> bool {
+        let matches = |filter: &Option<String>, value: &str| match filter {
+            Some(filter) => filter == value,
+            None => true,
+        };
+        matches(&self.actor, &metadata.actor)
+            && matches(&self.action, &metadata.action)
+            && matches(&self.target, &metadata.target)
+    }
+}
+
+/*
+ * Audit entries as returned by the json api
+ * */
+#[derive(Serialize, Debug)]
+pub struct AuditJson {
+    pub timestamp: u64,
+    pub actor: String,
+    pub action: &'static str,
+    /*
+     * The role given, for role changes
+     * */
+    pub role: Option<&'static str>,
+    /*
+     * When a suspension ends, for bans
+     * */
+    pub until: Option<u64>,
+    pub target: String,
+    pub reason: Option<String>,
+}
+
+impl From<&AuditEntry> for AuditJson {
+    fn from(entry: &AuditEntry) -> Self {
+        AuditJson {
+            timestamp: entry.timestamp,
+            actor: entry.actor.to_string(),
+            action: entry.action.name(),
+            role: match entry.action {
+                AuditAction::ChangeRole(role) => Some(role.name()),
+                _ => None,
+            },
+            until: match entry.action {
+                AuditAction::BanUser(until) => until,
+                _ => None,
+            },
+            target: entry.target.to_string(),
+            reason: entry.reason.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filters_match_every_field_set() {
+        let metadata = AuditMetadata::from(&AuditEntry {
+            timestamp: 0,
+            actor: "mod".to_string(),
+            action: AuditAction::RemovePost,
+            target: "a".to_string(),
+            reason: None,
+        });
+        assert!(AuditFilter::default().matches(&metadata));
+        let filter = AuditFilter {
+            actor: Some("mod".to_string()),
+            action: Some("remove".to_string()),
+            target: None,
+        };
+        assert!(filter.matches(&metadata));
+        let filter = AuditFilter {
+            action: Some("lock".to_string()),
+            ..filter
+        };
+        assert!(!filter.matches(&metadata));
+    }
+}
@@ -0,0 +1,285 @@
+use regex::Regex;
+use serde::Deserialize;
+
+/*
+ * What a filter does with content that it catches
+ * */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    #[default]
+    Reject,
+    /*
+     * Save it, but report it for moderators to review
+     * */
+    Flag,
+    /*
+     * Save a cleaned up version, filters that can't clean up content flag it instead
+     * */
+    Rewrite,
+}
+
+pub enum Verdict {
+    Allow,
+    Reject(String),
+    Flag(String),
+    Rewrite(String),
+}
+
+/*
+ * A single check on new content, run in order by the pipeline
+ * */
+pub trait ContentFilter {
+    fn check(&self, content: &str) -> Verdict;
+}
+
+/*
+ * Content that made it through the pipeline, possibly rewritten, with the reason for each
+ * filter that flagged it
+ * */
+#[derive(Debug, PartialEq)]
+pub struct Filtered {
+    pub content: String,
+    pub flags: Vec<String>,
+}
+
+/*
+ * Run content through every filter in turn, each one sees the content as rewritten by those
+ * before it. The first rejection stops the pipeline and its message is returned, as does
+ * rewriting the content to nothing
+ * */
+pub fn run(filters: &[Box<dyn ContentFilter>], content: &str) -> Result<Filtered, String> {
+    let mut filtered = Filtered {
+        content: content.to_string(),
+        flags: Vec::new(),
+    };
+    for filter in filters {
+        match filter.check(&filtered.content) {
+            Verdict::Allow => {}
+            Verdict::Reject(message) => return Err(message),
+            Verdict::Flag(reason) => filtered.flags.push(reason),
+            Verdict::Rewrite(content) => filtered.content = content,
+        }
+    }
+    if filtered.content.trim().is_empty() && !content.trim().is_empty() {
+        return Err("Error: your post has nothing left once filtered".to_string());
+    }
+    Ok(filtered)
+}
+
+/*
+ * Which filters run and how, stored as json in the filter config record or the CONTENT_FILTERS
+ * var. Filters that are left out don't run, e.g.
+ * {"banned_words": {"words": ["spam"], "action": "rewrite"}, "max_links": {"max": 2}}
+ * */
+#[derive(Deserialize, Debug, Default)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub banned_words: Option<BannedWords>,
+    #[serde(default)]
+    pub max_links: Option<LinkLimit>,
+    #[serde(default)]
+    pub max_repeated: Option<RepeatLimit>,
+}
+
+impl FilterConfig {
+    pub fn filters(self) -> Vec<Box<dyn ContentFilter>> {
+        let mut filters: Vec<Box<dyn ContentFilter>> = Vec::new();
+        // Before banned words, whose stars would otherwise count as repeated characters
+        if let Some(mut filter) = self.max_repeated {
+            // Shortening runs to nothing would empty the post
+            filter.max = filter.max.max(1);
+            filters.push(Box::new(filter));
+        }
+        if let Some(filter) = self.banned_words {
+            filters.push(Box::new(filter));
+        }
+        if let Some(filter) = self.max_links {
+            filters.push(Box::new(filter));
+        }
+        filters
+    }
+}
+
+/*
+ * Words that can't be used, matched whole and ignoring case. Rewriting stars them out
+ * */
+#[derive(Deserialize, Debug)]
+pub struct BannedWords {
+    pub words: Vec<String>,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+impl ContentFilter for BannedWords {
+    fn check(&self, content: &str) -> Verdict {
+        let banned = |word: &str| {
+            self.words
+                .iter()
+                .any(|banned| banned.to_lowercase() == word.to_lowercase())
+        };
+        let words = Regex::new(r"\w+").unwrap();
+        if !words.find_iter(content).any(|word| banned(word.as_str())) {
+            return Verdict::Allow;
+        }
+        match self.action {
+            FilterAction::Reject => {
+                Verdict::Reject("Error: your post contains words that aren't allowed".to_string())
+            }
+            FilterAction::Flag => Verdict::Flag("filter: banned words".to_string()),
+            FilterAction::Rewrite => Verdict::Rewrite(
+                words
+                    .replace_all(content, |word: &regex::Captures| match banned(&word[0]) {
+                        true => "*".repeat(word[0].chars().count()),
+                        false => word[0].to_string(),
+                    })
+                    .into_owned(),
+            ),
+        }
+    }
+}
+
+/*
+ * Most links a post can have, a common sign of spam
+ * */
+#[derive(Deserialize, Debug)]
+pub struct LinkLimit {
+    pub max: usize,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+impl ContentFilter for LinkLimit {
+    fn check(&self, content: &str) -> Verdict {
+        let links = Regex::new(r"(?i)\b(https?://|www\.)").unwrap();
+        if links.find_iter(content).count() <= self.max {
+            return Verdict::Allow;
+        }
+        match self.action {
+            FilterAction::Reject => {
+                Verdict::Reject(format!("Error: posts can have at most {} links", self.max))
+            }
+            FilterAction::Flag | FilterAction::Rewrite => {
+                Verdict::Flag("filter: too many links".to_string())
+            }
+        }
+    }
+}
+
+/*
+ * Longest run of a single repeated character, e.g. "!!!!!!!!". Rewriting shortens runs to the
+ * limit
+ * */
+#[derive(Deserialize, Debug)]
+pub struct RepeatLimit {
+    pub max: usize,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+impl ContentFilter for RepeatLimit {
+    fn check(&self, content: &str) -> Verdict {
+        let mut shortened = String::with_capacity(content.len());
+        let mut last = None;
+        let mut run = 0;
+        for c in content.chars() {
+            run = match last == Some(c) {
+                true => run + 1,
+                false => 1,
+            };
+            last = Some(c);
+            if run <= self.max {
+                shortened.push(c);
+            }
+        }
+        if shortened.len() == content.len() {
+            return Verdict::Allow;
+        }
+        match self.action {
+            FilterAction::Reject => Verdict::Reject(format!(
+                "Error: characters can be repeated at most {} times in a row",
+                self.max
+            )),
+            FilterAction::Flag => Verdict::Flag("filter: repeated characters".to_string()),
+            FilterAction::Rewrite => Verdict::Rewrite(shortened),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(json: &str) -> Vec<Box<dyn ContentFilter>> {
+        serde_json::from_str::<FilterConfig>(json)
+            .unwrap()
+            .filters()
+    }
+
+    #[test]
+    fn banned_words_match_whole_words() {
+        let filters = config(r#"{"banned_words": {"words": ["Spam"], "action": "rewrite"}}"#);
+        let filtered = run(&filters, "SPAM spammer, spam!").unwrap();
+        assert_eq!(filtered.content, "**** spammer, ****!");
+        assert!(filtered.flags.is_empty());
+
+        let filters = config(r#"{"banned_words": {"words": ["spam"]}}"#);
+        assert!(run(&filters, "spam").is_err());
+        assert!(run(&filters, "spammer").is_ok());
+    }
+
+    #[test]
+    fn links_are_limited() {
+        let filters = config(r#"{"max_links": {"max": 1, "action": "rewrite"}}"#);
+        let content = "see https://a.example and WWW.b.example";
+        let filtered = run(&filters, content).unwrap();
+        assert_eq!(filtered.content, content);
+        assert_eq!(filtered.flags, ["filter: too many links"]);
+        assert!(run(&filters, "just http://a.example")
+            .unwrap()
+            .flags
+            .is_empty());
+    }
+
+    #[test]
+    fn repeated_characters_are_shortened() {
+        let filters = config(r#"{"max_repeated": {"max": 3, "action": "rewrite"}}"#);
+        assert_eq!(run(&filters, "nooooo!!!!").unwrap().content, "nooo!!!");
+        assert_eq!(run(&filters, "hello").unwrap().content, "hello");
+
+        let filters = config(r#"{"max_repeated": {"max": 0, "action": "rewrite"}}"#);
+        assert_eq!(run(&filters, "aaa bb").unwrap().content, "a b");
+    }
+
+    struct Erase;
+
+    impl ContentFilter for Erase {
+        fn check(&self, _: &str) -> Verdict {
+            Verdict::Rewrite(" ".to_string())
+        }
+    }
+
+    #[test]
+    fn content_cannot_be_rewritten_to_nothing() {
+        let filters: Vec<Box<dyn ContentFilter>> = vec![Box::new(Erase)];
+        assert!(run(&filters, "something").is_err());
+        assert!(run(&filters, "").is_ok());
+    }
+
+    #[test]
+    fn pipeline_stops_at_the_first_rejection() {
+        let filters = config(
+            r#"{"banned_words": {"words": ["spam"], "action": "flag"},
+                "max_repeated": {"max": 2, "action": "rewrite"},
+                "max_links": {"max": 0}}"#,
+        );
+        let filtered = run(&filters, "spam spaaam").unwrap();
+        assert_eq!(filtered.content, "spam spaam");
+        assert_eq!(filtered.flags, ["filter: banned words"]);
+        assert_eq!(
+            run(&filters, "spam http://a.example"),
+            Err("Error: posts can have at most 0 links".to_string())
+        );
+        assert!(run(&config("{}"), "anything").unwrap().flags.is_empty());
+    }
+}
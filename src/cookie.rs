@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+/*
+ * Name of the cookie holding the session id
+ * */
+pub const SESSION_COOKIE: &str = "sessionId";
+
+/*
+ * Cookies sent in a Cookie header. Anything without a name and = is skipped, and where a name is
+ * repeated the first one wins, as browsers send the most specific cookie first
+ * */
+pub fn parse(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in header.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.is_empty() {
+            continue;
+        }
+        // Values may be quoted
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        cookies
+            .entry(name.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+    cookies
+}
+
+/*
+ * Set-Cookie value for a new session, expiring along with the session itself
+ * */
+pub fn session_cookie(session_id: &str, max_age: u64) -> String {
+    format!(
+        "{}={}; Max-Age={}; {}",
+        SESSION_COOKIE, session_id, max_age, ATTRIBUTES
+    )
+}
+
+/*
+ * Set-Cookie value that makes the browser remove the session cookie
+ * */
+pub fn clear_session_cookie() -> String {
+    format!("{}=; Max-Age=0; {}", SESSION_COOKIE, ATTRIBUTES)
+}
+
+/*
+ * Not readable from scripts and only sent over https. Lax still sends the cookie when following a
+ * link to the site, but not with posts from other sites
+ * */
+const ATTRIBUTES: &str = "Path=/; HttpOnly; Secure; SameSite=Lax";
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_cookies() {
+        let cookies = parse("sessionId=abc; theme=\"dark\";flag; =empty; a=b=c");
+        assert_eq!(cookies.get("sessionId").map(String::as_str), Some("abc"));
+        assert_eq!(cookies.get("theme").map(String::as_str), Some("dark"));
+        assert_eq!(cookies.get("a").map(String::as_str), Some("b=c"));
+        assert!(!cookies.contains_key("flag"));
+        assert!(!cookies.contains_key(""));
+    }
+
+    #[test]
+    fn parse_malformed_cookies() {
+        assert!(parse("").is_empty());
+        assert!(parse(";;;").is_empty());
+        assert!(parse("nonsense").is_empty());
+        let cookies = parse("sessionId=first; sessionId=second");
+        assert_eq!(cookies.get("sessionId").map(String::as_str), Some("first"));
+    }
+
+    #[test]
+    fn session_cookies() {
+        let cookie = session_cookie("abc", 60);
+        assert!(cookie.starts_with("sessionId=abc; Max-Age=60; "));
+        for attribute in &["Path=/", "HttpOnly", "Secure", "SameSite=Lax"] {
+            assert!(cookie.contains(attribute), "{}", attribute);
+        }
+        assert_eq!(parse(&cookie).get(SESSION_COOKIE).unwrap(), "abc");
+
+        let cookie = clear_session_cookie();
+        assert!(cookie.starts_with("sessionId=; Max-Age=0; "));
+        assert!(cookie.contains("Path=/"));
+    }
+}
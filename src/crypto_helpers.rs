@@ -2,6 +2,10 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
 
 pub fn hash_password(password: &str) -> String {
     let salt = SaltString::generate(&mut OsRng);
@@ -26,6 +30,52 @@ pub fn verify_password(password: &str, hash: &str) -> bool {
         .is_ok()
 }
 
+/*
+ * Token of the form {id}.{signature}, so that forged tokens are rejected before they are looked up
+ * */
+pub fn sign_token(secret: &str, id: &str) -> String {
+    format!("{}.{}", id, signature(secret, id))
+}
+
+/*
+ * Hex hmac of a message, which can't be produced without the secret
+ * */
+pub fn signature(secret: &str, message: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap(); // Any key length works
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/*
+ * The id a token was signed for, None if the signature doesn't match
+ * */
+pub fn verify_token<'a>(secret: &str, token: &'a str) -> Option<&'a str> {
+    let (id, signature) = token.split_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(id.as_bytes());
+    mac.verify(&signature).ok()?;
+    Some(id)
+}
+
+/*
+ * Compare against a configured secret in constant time
+ * */
+pub fn secret_matches(secret: &str, candidate: &str) -> bool {
+    let mut expected = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    expected.update(secret.as_bytes());
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(candidate.as_bytes());
+    mac.verify(&expected.finalize().into_bytes()).is_ok()
+}
+
+/*
+ * Identifies a secret, e.g. a session id, in pages and forms without revealing it
+ * */
+pub fn public_id(secret: &str) -> String {
+    hex::encode(&Sha256::digest(secret.as_bytes())[..8])
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -46,6 +96,30 @@ mod test {
         assert!(verify_password(password, &hash1));
 
         let password2 = "password1234";
-        assert_eq!(verify_password(password2, &hash1), false);
+        assert!(!verify_password(password2, &hash1));
+    }
+
+    #[test]
+    fn signed_tokens() {
+        let token = sign_token("secret", "abc");
+        assert_eq!(verify_token("secret", &token), Some("abc"));
+        assert_eq!(verify_token("other", &token), None);
+        assert_eq!(verify_token("secret", &token.replace("abc", "abd")), None);
+        assert_eq!(verify_token("secret", "abc"), None);
+        assert_eq!(verify_token("secret", "abc.zz"), None);
+    }
+
+    #[test]
+    fn secrets_match_exactly() {
+        assert!(secret_matches("secret", "secret"));
+        assert!(!secret_matches("secret", "secret "));
+        assert!(!secret_matches("secret", ""));
+    }
+
+    #[test]
+    fn public_ids() {
+        assert_eq!(public_id("a"), public_id("a"));
+        assert_ne!(public_id("a"), public_id("b"));
+        assert_eq!(public_id("a").len(), 16);
     }
 }
@@ -0,0 +1,155 @@
+use crate::crypto_helpers;
+use crate::db::store::Backend;
+use crate::http::{HttpRequest, HttpResponse};
+use worker::*;
+
+/*
+ * Form field the token is submitted in
+ * */
+pub const CSRF_FIELD: &str = "csrf";
+
+/*
+ * Header the token is sent in by api requests that use the session cookie
+ * */
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/*
+ * Token for the forms of a session. Derived from the session id rather than stored, so it changes
+ * whenever the user logs in again and can't be guessed without the secret
+ * */
+pub fn token<E: Backend>(env: &E, session_id: &str) -> Result<String> {
+    Ok(crypto_helpers::signature(
+        &env.var("TOKEN_SECRET")?,
+        &format!("csrf:{}", session_id),
+    ))
+}
+
+pub fn verify<E: Backend>(env: &E, session_id: &str, candidate: Option<&str>) -> Result<bool> {
+    match candidate {
+        Some(candidate) => Ok(crypto_helpers::secret_matches(
+            &token(env, session_id)?,
+            candidate,
+        )),
+        None => Ok(false),
+    }
+}
+
+/*
+ * Add the token to every POST form on an html page. User content is escaped, so the only form tags
+ * are the ones from the templates. GET forms are left alone, they would put the token in the url
+ * */
+pub fn add_to_forms(response: HttpResponse, token: &str) -> HttpResponse {
+    if response.header("Content-Type") != Some("text/html") {
+        return response;
+    }
+    let input = format!(
+        "<input type=\"hidden\" name=\"{}\" value=\"{}\"></form>",
+        CSRF_FIELD, token
+    );
+    let mut forms = response.body.split("</form>").peekable();
+    let mut body = String::with_capacity(response.body.len());
+    while let Some(form) = forms.next() {
+        body.push_str(form);
+        if forms.peek().is_none() {
+            break;
+        }
+        let opening = form
+            .rfind("<form")
+            .map(|start| &form[start..])
+            .and_then(|tag| tag.split('>').next())
+            .unwrap_or_default();
+        match opening.to_lowercase().contains("method=\"post\"") {
+            true => body.push_str(input.as_str()),
+            false => body.push_str("</form>"),
+        }
+    }
+    HttpResponse { body, ..response }
+}
+
+/*
+ * Whether a request could have come from one of the site's own pages, going by the Origin header or
+ * failing that the Referer. Requests with neither, e.g. from api clients or browsers that strip
+ * them, are let through and left to the token check
+ * */
+pub fn same_origin(req: &HttpRequest) -> bool {
+    let host = match req.header("Host") {
+        Some(host) => host,
+        None => return true,
+    };
+    let source = match req.header("Origin").or_else(|| req.header("Referer")) {
+        Some(source) => source,
+        None => return true,
+    };
+    match url::Url::parse(source) {
+        Ok(url) => match (url.host_str(), url.port()) {
+            (Some(source_host), Some(port)) => format!("{}:{}", source_host, port) == host,
+            (Some(source_host), None) => source_host == host,
+            _ => false,
+        },
+        // e.g. Origin: null from sandboxed frames
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+
+    #[test]
+    fn tokens_are_per_session() {
+        let env = MemoryBackend::new();
+        let token = token(&env, "a").unwrap();
+        assert!(verify(&env, "a", Some(&token)).unwrap());
+        assert!(!verify(&env, "b", Some(&token)).unwrap());
+        assert!(!verify(&env, "a", Some("")).unwrap());
+        assert!(!verify(&env, "a", None).unwrap());
+    }
+
+    #[test]
+    fn tokens_are_added_to_html_forms() {
+        let html = HttpResponse::from_html(
+            "<form method=\"POST\"></form><form method=\"GET\"></form><form method=\"post\"></form>",
+        )
+        .unwrap();
+        let body = add_to_forms(html, "abc").body;
+        assert_eq!(
+            body,
+            "<form method=\"POST\"><input type=\"hidden\" name=\"csrf\" value=\"abc\"></form>\
+             <form method=\"GET\"></form>\
+             <form method=\"post\"><input type=\"hidden\" name=\"csrf\" value=\"abc\"></form>"
+        );
+
+        let json = HttpResponse::from_json(&"</form>").unwrap();
+        assert_eq!(add_to_forms(json, "abc").body, "\"</form>\"");
+    }
+
+    #[test]
+    fn origins_are_checked() {
+        let request = |headers: &[(&str, &str)]| {
+            let mut req = HttpRequest::new(Method::Post, "/");
+            for (name, value) in headers {
+                req.headers.insert(name.to_lowercase(), value.to_string());
+            }
+            same_origin(&req)
+        };
+        let host = ("Host", "example.com");
+        assert!(request(&[host]));
+        assert!(request(&[host, ("Origin", "https://example.com")]));
+        assert!(request(&[host, ("Referer", "https://example.com/a?b")]));
+        assert!(!request(&[host, ("Origin", "https://evil.com")]));
+        assert!(!request(&[
+            host,
+            ("Referer", "https://example.com.evil.com/")
+        ]));
+        assert!(!request(&[host, ("Origin", "null")]));
+        assert!(request(&[
+            ("Host", "127.0.0.1:8787"),
+            ("Origin", "http://127.0.0.1:8787")
+        ]));
+        assert!(!request(&[
+            ("Host", "127.0.0.1:8787"),
+            ("Origin", "http://127.0.0.1:9000")
+        ]));
+    }
+}
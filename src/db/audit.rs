@@ -0,0 +1,163 @@
+use crate::audit_obj::{AuditAction, AuditEntry, AuditFilter, AuditMetadata};
+use crate::db::store::{Backend, Store, MAX_LIST_LIMIT};
+use crate::user_obj;
+use uuid::Uuid;
+use worker::*;
+
+/*
+ * Append an action to the audit log. Entries are never changed or removed
+ * */
+pub async fn record<E: Backend>(
+    env: &E,
+    actor: &user_obj::User,
+    action: AuditAction,
+    target: &str,
+    reason: Option<&str>,
+) -> Result<()> {
+    let entry = AuditEntry {
+        timestamp: env.now(),
+        actor: actor.user_id.to_string(),
+        action,
+        target: target.to_string(),
+        reason: reason
+            .map(str::trim)
+            .filter(|reason| !reason.is_empty())
+            .map(str::to_string),
+    };
+    env.store("USERS")?
+        .put_with_metadata(
+            get_audit_key(entry.timestamp).as_str(),
+            serde_json::to_string(&entry)?.as_str(),
+            &serde_json::to_value(AuditMetadata::from(&entry))?,
+        )
+        .await
+}
+
+/*
+ * A page of entries matching the filter, newest first, and the cursor for the next page. At most
+ * MAX_LIST_LIMIT entries are looked at per page, so a narrow filter can return a short or even
+ * empty page that still has a cursor
+ * */
+pub async fn list<E: Backend>(
+    env: &E,
+    filter: &AuditFilter,
+    cursor: Option<&str>,
+    limit: u64,
+) -> Result<(Vec<AuditEntry>, Option<String>)> {
+    let kv = env.store("USERS")?;
+    let mut entries = Vec::new();
+    let mut cursor = cursor.map(str::to_string);
+    let mut scanned = 0;
+    // Each listing asks for no more than are still needed, so that a page never ends part way
+    // through a listing and the cursor can't skip past entries
+    while (entries.len() as u64) < limit && scanned < MAX_LIST_LIMIT {
+        let page = kv
+            .list_page(
+                AUDIT_PREFIX,
+                cursor.as_deref(),
+                (limit - entries.len() as u64).min(MAX_LIST_LIMIT - scanned),
+            )
+            .await?;
+        scanned += page.keys.len() as u64;
+        for key in page.keys {
+            let metadata = key
+                .metadata
+                .and_then(|metadata| serde_json::from_value::<AuditMetadata>(metadata).ok());
+            if matches!(&metadata, Some(metadata) if !filter.matches(metadata)) {
+                continue;
+            }
+            let entry = match kv.get(key.name.as_str()).await? {
+                Some(entry) => serde_json::from_str::<AuditEntry>(&entry)?,
+                None => continue,
+            };
+            if metadata.is_some() || filter.matches(&AuditMetadata::from(&entry)) {
+                entries.push(entry);
+            }
+        }
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    Ok((entries, cursor))
+}
+
+const AUDIT_PREFIX: &str = "audit:";
+
+/*
+ * The log shares the USERS namespace. Keys count down from the end of time so that listing them
+ * returns the newest first, the uuid keeps entries made in the same millisecond apart
+ * */
+fn get_audit_key(timestamp: u64) -> String {
+    format!(
+        "{}{:020}:{}",
+        AUDIT_PREFIX,
+        u64::MAX - timestamp,
+        Uuid::new_v4().to_simple()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use futures::executor::block_on;
+
+    fn moderator(user_id: &str) -> user_obj::User {
+        user_obj::User {
+            account: user_obj::UserAccount {
+                email: String::new(),
+                hash: String::new(),
+                username: user_id.to_string(),
+                status: user_obj::AccountStatus::Active,
+                role: user_obj::Role::Moderator,
+                ban: None,
+            },
+            user_id: user_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn entries_are_filtered_and_paged_newest_first() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            for post_id in &["a", "b", "c", "d", "e"] {
+                record(&env, &moderator("m"), AuditAction::LockPost, post_id, None)
+                    .await
+                    .unwrap();
+                env.advance(1);
+                record(
+                    &env,
+                    &moderator("n"),
+                    AuditAction::RemovePost,
+                    post_id,
+                    None,
+                )
+                .await
+                .unwrap();
+                env.advance(1);
+            }
+
+            let filter = AuditFilter {
+                actor: Some("m".to_string()),
+                ..Default::default()
+            };
+            let (entries, cursor) = list(&env, &filter, None, 2).await.unwrap();
+            let targets: Vec<_> = entries.iter().map(|entry| entry.target.as_str()).collect();
+            assert_eq!(targets, ["e", "d"]);
+            let (entries, _) = list(&env, &filter, cursor.as_deref(), 10).await.unwrap();
+            let targets: Vec<_> = entries.iter().map(|entry| entry.target.as_str()).collect();
+            assert_eq!(targets, ["c", "b", "a"]);
+
+            let filter = AuditFilter {
+                action: Some("remove".to_string()),
+                target: Some("b".to_string()),
+                ..Default::default()
+            };
+            let (entries, cursor) = list(&env, &filter, None, 10).await.unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].actor, "n");
+            assert_eq!(cursor, None);
+        });
+    }
+}
@@ -0,0 +1,266 @@
+use crate::db::store::{Backend, ListKey, ListPage, Store};
+use crate::mail::{Mail, MailSender};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use worker::*;
+
+struct Entry {
+    value: String,
+    expiry: Option<Instant>,
+    metadata: Option<Value>,
+}
+
+type Entries = BTreeMap<String, Entry>;
+
+/*
+ * In-memory store, used to run the db layer natively e.g. in tests
+ * */
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    entries: Rc<RefCell<Entries>>,
+}
+
+impl MemoryStore {
+    fn live_entries(&self) -> RefMut<'_, Entries> {
+        let mut entries = self.entries.borrow_mut();
+        let now = Instant::now();
+        entries.retain(|_, entry| !matches!(entry.expiry, Some(expiry) if expiry <= now));
+        entries
+    }
+
+    fn insert(&self, key: &str, value: &str, expiry: Option<Instant>, metadata: Option<Value>) {
+        let entry = Entry {
+            value: value.to_string(),
+            expiry,
+            metadata,
+        };
+        self.live_entries().insert(key.to_string(), entry);
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .live_entries()
+            .get(key)
+            .map(|entry| entry.value.to_owned()))
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.insert(key, value, None, None);
+        Ok(())
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: u64) -> Result<()> {
+        let expiry = Instant::now() + Duration::from_secs(ttl);
+        self.insert(key, value, Some(expiry), None);
+        Ok(())
+    }
+
+    async fn put_with_metadata(&self, key: &str, value: &str, metadata: &Value) -> Result<()> {
+        self.insert(key, value, None, Some(metadata.clone()));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.live_entries().remove(key);
+        Ok(())
+    }
+
+    /*
+     * The cursor is simply the last key of the previous page
+     * */
+    async fn list_page(&self, prefix: &str, cursor: Option<&str>, limit: u64) -> Result<ListPage> {
+        let mut keys = self
+            .live_entries()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| !matches!(cursor, Some(cursor) if key.as_str() <= cursor))
+            .take(limit as usize + 1)
+            .map(|(key, entry)| ListKey {
+                name: key.to_string(),
+                metadata: entry.metadata.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        let cursor = if keys.len() > limit as usize {
+            keys.truncate(limit as usize);
+            keys.last().map(|key| key.name.to_string())
+        } else {
+            None
+        };
+        Ok(ListPage { keys, cursor })
+    }
+}
+
+/*
+ * Keeps sent mail so that tests can read it
+ * */
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    outbox: Rc<RefCell<Vec<Mail>>>,
+}
+
+#[async_trait(?Send)]
+impl MailSender for MemoryMailer {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        self.outbox.borrow_mut().push(mail.clone());
+        Ok(())
+    }
+}
+
+/*
+ * In-memory backend, stores are created on first use
+ * */
+#[derive(Default)]
+pub struct MemoryBackend {
+    stores: RefCell<HashMap<String, MemoryStore>>,
+    vars: HashMap<String, String>,
+    mailer: MemoryMailer,
+    now: Cell<u64>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        let mut backend = Self::default();
+        backend.set_var("SESSION_EXPIRY", "43200");
+        backend.set_var("SITE_URL", "http://localhost");
+        backend.set_var("TOKEN_SECRET", "secret");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        backend.now.set(now.as_millis() as u64);
+        backend
+    }
+
+    /*
+     * Move the backend's clock forward, the stores themselves still expire in real time
+     * */
+    pub fn advance(&self, millis: u64) {
+        self.now.set(self.now.get() + millis);
+    }
+
+    /*
+     * Every mail sent so far, oldest first
+     * */
+    pub fn outbox(&self) -> Vec<Mail> {
+        self.mailer.outbox.borrow().clone()
+    }
+
+    pub fn set_var<S: AsRef<str>, S2: AsRef<str>>(&mut self, name: S, value: S2) {
+        self.vars
+            .insert(name.as_ref().to_string(), value.as_ref().to_string());
+    }
+}
+
+impl Backend for MemoryBackend {
+    type Store = MemoryStore;
+    type Mailer = MemoryMailer;
+
+    fn store(&self, binding: &str) -> Result<MemoryStore> {
+        Ok(self
+            .stores
+            .borrow_mut()
+            .entry(binding.to_string())
+            .or_default()
+            .clone())
+    }
+
+    fn var(&self, name: &str) -> Result<String> {
+        self.vars
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::BindingError(name.to_string()))
+    }
+
+    fn mailer(&self) -> MemoryMailer {
+        self.mailer.clone()
+    }
+
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn memory_store_roundtrip() {
+        let store = MemoryStore::default();
+        block_on(async {
+            assert_eq!(store.get("a").await.unwrap(), None);
+            store.put("a", "1").await.unwrap();
+            assert_eq!(store.get("a").await.unwrap(), Some("1".to_string()));
+            store.delete("a").await.unwrap();
+            assert_eq!(store.get("a").await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn memory_store_list_prefix() {
+        let store = MemoryStore::default();
+        block_on(async {
+            store.put("ab", "").await.unwrap();
+            store.put("aa", "").await.unwrap();
+            store.put("b", "").await.unwrap();
+            assert_eq!(store.list("a").await.unwrap(), vec!["aa", "ab"]);
+        });
+    }
+
+    #[test]
+    fn memory_store_list_pages() {
+        let store = MemoryStore::default();
+        block_on(async {
+            for key in &["a1", "a2", "a3", "b1"] {
+                store.put(key, "").await.unwrap();
+            }
+            store
+                .put_with_metadata("a3", "", &serde_json::json!({"n": 3}))
+                .await
+                .unwrap();
+            let page = store.list_page("a", None, 2).await.unwrap();
+            let names = page
+                .keys
+                .iter()
+                .map(|key| key.name.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec!["a1", "a2"]);
+            let page = store
+                .list_page("a", page.cursor.as_deref(), 2)
+                .await
+                .unwrap();
+            assert_eq!(page.keys[0].name, "a3");
+            assert_eq!(page.keys[0].metadata, Some(serde_json::json!({"n": 3})));
+            assert!(page.cursor.is_none());
+        });
+    }
+
+    #[test]
+    fn memory_store_ttl() {
+        let store = MemoryStore::default();
+        block_on(async {
+            store.put_with_ttl("a", "1", 0).await.unwrap();
+            assert_eq!(store.get("a").await.unwrap(), None);
+            store.put_with_ttl("b", "1", 60).await.unwrap();
+            assert_eq!(store.get("b").await.unwrap(), Some("1".to_string()));
+        });
+    }
+
+    #[test]
+    fn memory_backend_shares_stores() {
+        let backend = MemoryBackend::new();
+        block_on(async {
+            backend.store("POSTS").unwrap().put("a", "1").await.unwrap();
+            let value = backend.store("POSTS").unwrap().get("a").await.unwrap();
+            assert_eq!(value, Some("1".to_string()));
+            assert!(backend.var("SESSION_EXPIRY").is_ok());
+            assert!(backend.var("MISSING").is_err());
+        });
+    }
+}
@@ -1,2 +1,8 @@
+pub mod audit;
+#[cfg(test)]
+pub mod memory;
 pub mod post;
+pub mod report;
+pub mod store;
+pub mod throttle;
 pub mod user;
@@ -1,21 +1,35 @@
+use crate::content_filter::FilterConfig;
+use crate::db::report;
+use crate::db::store::{Backend, Store, MAX_LIST_LIMIT};
 use crate::db::user;
 use crate::post_obj;
 
 use crate::user_obj;
+use crate::utils;
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use uuid::Uuid;
 use worker::*;
 
-pub async fn get_content(env: &Env, post_id: &str) -> Result<Option<post_obj::PostTitle>> {
+pub async fn get_content<E: Backend>(
+    env: &E,
+    post_id: &str,
+) -> Result<Option<post_obj::PostTitle>> {
     let prefix = get_prefix(post_id, 0);
 
     // get data
-    let data = env.kv("POSTS")?.get(prefix.as_str()).await?;
+    let data = env.store("POSTS")?.get(prefix.as_str()).await?;
     match data {
         None => Ok(None),
         Some(content) => {
-            let post: post_obj::Post = serde_json::from_str(content.as_string().as_str())?;
-            let user: Option<user_obj::User> = user::get_user(env, &post.user).await?;
+            let post: post_obj::Post = serde_json::from_str(content.as_str())?;
+            // Tombstones are not attributed to anyone
+            let user: Option<user_obj::User> = match post.deleted {
+                true => None,
+                false => user::get_user(env, &post.user).await?,
+            };
             Ok(Some(post_obj::PostTitle {
                 title: post_id.to_string(),
                 post,
@@ -27,50 +41,210 @@ pub async fn get_content(env: &Env, post_id: &str) -> Result<Option<post_obj::Po
     // convert to string and return
 }
 
-pub async fn post_content(
-    env: &Env,
+pub async fn post_content<E: Backend>(
+    env: &E,
     post_id: &str,
     contents: &str,
     user: user_obj::User,
 ) -> Result<()> {
-    let kv = env.kv("POSTS")?;
-    let prefix = get_prefix(post_id, 0);
+    let kv = env.store("POSTS")?;
 
     let contents = html_escape::encode_text(contents);
 
     let post = post_obj::Post {
         user: user.user_id,
         content: contents.to_string(),
+        version: post_obj::POST_SCHEMA_VERSION,
+        created_at: Some(env.now()),
+        updated_at: None,
+        reply_count: 0,
+        deleted: false,
+        removed: false,
+        score: 0,
     };
-    let post_string = serde_json::to_string(&post)?;
-    kv.put(prefix.as_str(), post_string)?.execute().await?;
+    put_post(&kv, post_id, &post).await?;
+
+    if let Some(parent_id) = get_parent_id(post_id) {
+        update_reply_count(&kv, parent_id, 1).await?;
+    }
     Ok(())
 }
 
-pub async fn get_replies(env: &Env, post_id: &str) -> Result<Vec<post_obj::PostTitle>> {
+/*
+ * Replace the content of a post, keeping the previous content as a revision
+ * */
+pub async fn edit_post<E: Backend>(env: &E, post_id: &str, contents: &str) -> Result<()> {
+    let kv = env.store("POSTS")?;
+
+    let mut post = match get_post(&kv, post_id).await? {
+        Some(post) => post,
+        None => return Err(Error::RustError(String::from("Cannot edit a missing post"))),
+    };
+
+    let contents = html_escape::encode_text(contents);
+
+    let revision = post_obj::Revision {
+        timestamp: env.now(),
+        content: post.content,
+    };
+    kv.put(
+        get_revision_key(post_id, revision.timestamp).as_str(),
+        serde_json::to_string(&revision)?.as_str(),
+    )
+    .await?;
+
+    post.content = contents.to_string();
+    post.updated_at = Some(revision.timestamp);
+    put_post(&kv, post_id, &post).await
+}
+
+/*
+ * Previous versions of a post, oldest first
+ * */
+pub async fn get_revisions<E: Backend>(env: &E, post_id: &str) -> Result<Vec<post_obj::Revision>> {
+    let kv = env.store("POSTS")?;
+    let keys = kv.list(get_revision_prefix(post_id).as_str()).await?;
+
+    let mut revisions = Vec::new();
+    for key in keys {
+        if let Some(data) = kv.get(key.as_str()).await? {
+            revisions.push(serde_json::from_str(data.as_str())?);
+        }
+    }
+    Ok(revisions)
+}
+
+/*
+ * Revisions share the POSTS namespace; they never start with whitespace so never show up as replies.
+ * The timestamp is zero padded so that keys list in chronological order, the uuid keeps edits made
+ * in the same millisecond apart
+ * */
+fn get_revision_prefix(post_id: &str) -> String {
+    format!("revision:{}:", post_id)
+}
+
+fn get_revision_key(post_id: &str, timestamp: u64) -> String {
+    format!(
+        "{}{:020}:{}",
+        get_revision_prefix(post_id),
+        timestamp,
+        Uuid::new_v4().to_simple()
+    )
+}
+
+/*
+ * A page of at most `limit` replies in the given order, along with the cursor for the next page
+ * if there is one
+ * */
+pub async fn get_replies_page<E: Backend>(
+    env: &E,
+    post_id: &str,
+    sort: post_obj::SortOrder,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<post_obj::PostTitle>, Option<String>)> {
+    let kv = env.store("POSTS")?;
+    let (keys, cursor) = match sort {
+        post_obj::SortOrder::Alphabetical => list_replies(&kv, post_id, cursor, limit).await?,
+        sort => list_sorted_replies(&kv, post_id, sort, cursor, limit).await?,
+    };
+    Ok((get_posts(env, &keys).await, cursor))
+}
+
+/*
+ * Replies in key order, paginated with the store's own cursor
+ * */
+async fn list_replies<S: Store>(
+    kv: &S,
+    post_id: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<String>, Option<String>)> {
     let prefix = get_prefix(post_id, 1);
+    // The root post's own key matches its reply prefix and sorts before every reply
+    let limit = match (post_id, cursor) {
+        ("", None) => limit + 1,
+        _ => limit,
+    };
 
-    // get list of keys with correct prefix
-    let keys = env.kv("POSTS")?.list().prefix(prefix).execute().await?;
+    let page = kv.list_page(prefix.as_str(), cursor, limit as u64).await?;
+    let keys = page.keys.into_iter().map(|key| key.name).collect();
+    Ok((keys, page.cursor))
+}
 
-    // get content for each key
-    let values = keys
+/*
+ * Replies ordered by their metadata. A post has at most one reply per title character, so every
+ * reply fits in a single listing and the cursor is just the offset into the sorted replies
+ * */
+async fn list_sorted_replies<S: Store>(
+    kv: &S,
+    post_id: &str,
+    sort: post_obj::SortOrder,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<String>, Option<String>)> {
+    let page = kv
+        .list_page(get_prefix(post_id, 1).as_str(), None, MAX_LIST_LIMIT)
+        .await?;
+    let mut replies = page
         .keys
-        .iter()
+        .into_iter()
+        .filter(|key| key.name.rfind(|c: char| !c.is_whitespace()).is_some())
+        .map(|key| {
+            let metadata: post_obj::PostMetadata = key
+                .metadata
+                .and_then(|metadata| serde_json::from_value(metadata).ok())
+                .unwrap_or_default();
+            (key.name, metadata)
+        })
+        .collect::<Vec<_>>();
+
+    // Sorts are stable, so ties stay in key order
+    match sort {
+        post_obj::SortOrder::Alphabetical => {}
+        post_obj::SortOrder::Newest => {
+            replies.sort_by_key(|(_, metadata)| Reverse(metadata.created_at))
+        }
+        post_obj::SortOrder::Oldest => replies.sort_by_key(|(_, metadata)| metadata.created_at),
+        post_obj::SortOrder::MostReplies => {
+            replies.sort_by_key(|(_, metadata)| Reverse(metadata.reply_count))
+        }
+        post_obj::SortOrder::Top => replies.sort_by_key(|(_, metadata)| Reverse(metadata.score)),
+    }
+
+    let offset = cursor
+        .and_then(|cursor| cursor.parse::<usize>().ok())
+        .unwrap_or(0);
+    let next_offset = offset + limit;
+    let cursor = Some(next_offset.to_string()).filter(|_| next_offset < replies.len());
+    let keys = replies
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(key, _)| key)
+        .collect();
+    Ok((keys, cursor))
+}
+
+async fn get_posts<E: Backend>(env: &E, keys: &[String]) -> Vec<post_obj::PostTitle> {
+    // get content for each key
+    keys.iter()
         // Ignore the case when the entire key is whitespace e.g. root post (root post is never a
         // child of another post)
-        .filter(|key| key.name.rfind(|c: char| !c.is_whitespace()).is_some())
+        .filter(|key| key.rfind(|c: char| !c.is_whitespace()).is_some())
         .map(|key| async move {
-            let key_name = key.name.as_str().trim_start();
-            let kv = env.kv("POSTS")?;
-            if let Some(body) = kv.get(key.name.as_str()).await? {
-                let body = body.as_string();
+            let key_name = key.as_str().trim_start();
+            let kv = env.store("POSTS")?;
+            if let Some(body) = kv.get(key.as_str()).await? {
                 let post: post_obj::Post = serde_json::from_str(body.as_str())?;
-                let user = post.user.to_string();
+                let user = match post.deleted {
+                    true => None,
+                    false => user::get_user(env, &post.user).await?,
+                };
                 let post_title = post_obj::PostTitle {
                     title: key_name.to_string(),
                     post,
-                    user: user::get_user(env, user).await?,
+                    user,
                 };
                 return worker::Result::Ok(post_title);
             }
@@ -82,8 +256,86 @@ pub async fn get_replies(env: &Env, post_id: &str) -> Result<Vec<post_obj::PostT
         .collect::<FuturesOrdered<_>>()
         .filter_map(|v| async { v.ok() })
         .collect::<Vec<_>>()
-        .await;
-    Ok(values)
+        .await
+}
+
+/*
+ * Replies to a post down to the given depth, fetched a level at a time with each level's reads
+ * running concurrently. Each post shows at most `page_size` replies, the top level starting from
+ * `cursor`. Stops expanding once roughly `limit` posts have been fetched.
+ * Returns the cursor for the next page of top level replies
+ * */
+pub async fn get_subtree<E: Backend>(
+    env: &E,
+    post_id: &str,
+    sort: post_obj::SortOrder,
+    cursor: Option<&str>,
+    page_size: usize,
+    depth: usize,
+    limit: usize,
+) -> Result<(Vec<post_obj::ReplyTree>, Option<String>)> {
+    let mut children: HashMap<String, Vec<post_obj::PostTitle>> = HashMap::new();
+    let (replies, next_cursor) = get_replies_page(env, post_id, sort, cursor, page_size).await?;
+    let mut level = vec![(post_id.to_string(), Ok((replies, None)))];
+    let mut fetched = 0;
+
+    for current_depth in 1..=depth {
+        let mut next_frontier = Vec::new();
+        for (parent_id, replies) in level {
+            let (replies, _) = replies?;
+            fetched += replies.len();
+            for reply in &replies {
+                // Posts from before reply counts were stored might still have replies
+                if reply.post.reply_count > 0 || reply.post.version < 1 {
+                    next_frontier.push((reply.title.to_string(), reply.post.reply_count.max(1)));
+                }
+            }
+            children.insert(parent_id.to_string(), replies);
+        }
+
+        // Only expand as many posts as the remaining budget is expected to cover
+        let mut budget = limit.saturating_sub(fetched);
+        let frontier = next_frontier
+            .into_iter()
+            .take_while(|(_, reply_count)| {
+                let reply_count = (*reply_count as usize).min(page_size);
+                let fits = reply_count <= budget;
+                budget = budget.saturating_sub(reply_count);
+                fits
+            })
+            .map(|(post_id, _)| post_id)
+            .collect::<Vec<_>>();
+        if frontier.is_empty() || current_depth == depth {
+            break;
+        }
+
+        level = frontier
+            .into_iter()
+            .map(|post_id| async move {
+                let replies = get_replies_page(env, &post_id, sort, None, page_size).await;
+                (post_id, replies)
+            })
+            .collect::<FuturesOrdered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+    }
+
+    Ok((assemble_subtree(&mut children, post_id), next_cursor))
+}
+
+fn assemble_subtree(
+    children: &mut HashMap<String, Vec<post_obj::PostTitle>>,
+    post_id: &str,
+) -> Vec<post_obj::ReplyTree> {
+    children
+        .remove(post_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|post| {
+            let replies = assemble_subtree(children, &post.title);
+            post_obj::ReplyTree { post, replies }
+        })
+        .collect()
 }
 
 /*
@@ -99,9 +351,769 @@ fn get_prefix(post_id: &str, offset: usize) -> String {
     format!("{}{}", zeros, post_id)
 }
 
-pub async fn delete_post(env: &Env, post_id: &str) -> Result<()> {
-    let kv = env.kv("POSTS")?;
-    let post_id = get_prefix(post_id, 0);
-    kv.delete(&post_id).await?;
+/*
+ * Replace a post with a tombstone, the post stays in place so its replies can still be reached
+ * */
+pub async fn delete_post<E: Backend>(env: &E, post_id: &str) -> Result<()> {
+    let kv = env.store("POSTS")?;
+    let mut post = match get_post(&kv, post_id).await? {
+        Some(post) => post,
+        None => return Ok(()),
+    };
+
+    post.deleted = true;
+    post.content = String::new();
+    put_post(&kv, post_id, &post).await?;
+
+    // Previous versions would otherwise still be visible in the history
+    for key in kv.list(get_revision_prefix(post_id).as_str()).await? {
+        kv.delete(key.as_str()).await?;
+    }
     Ok(())
 }
+
+/*
+ * Hide a post, or show it again, as a moderator. Nothing is deleted so that it can be restored
+ * */
+pub async fn set_removed<E: Backend>(env: &E, post_id: &str, removed: bool) -> Result<()> {
+    let kv = env.store("POSTS")?;
+    if let Some(mut post) = get_post(&kv, post_id).await? {
+        post.removed = removed;
+        put_post(&kv, post_id, &post).await?;
+    }
+    Ok(())
+}
+
+/*
+ * Stop, or allow again, new replies anywhere below a post
+ * */
+pub async fn set_locked<E: Backend>(env: &E, post_id: &str, locked: bool) -> Result<()> {
+    let kv = env.store("POSTS")?;
+    let key = format!("{}{}", LOCK_PREFIX, post_id);
+    match locked {
+        true => kv.put(key.as_str(), "").await,
+        false => kv.delete(key.as_str()).await,
+    }
+}
+
+/*
+ * The locked post a post is in the subtree of, possibly itself, None if it can be replied to
+ * */
+pub async fn get_lock<E: Backend>(env: &E, post_id: &str) -> Result<Option<String>> {
+    // Locks are rare, so a single listing is cheaper than looking up every ancestor
+    let locks = env.store("POSTS")?.list(LOCK_PREFIX).await?;
+    Ok(locks
+        .into_iter()
+        .map(|key| key[LOCK_PREFIX.len()..].to_string())
+        .find(|locked_id| post_id.starts_with(locked_id.as_str())))
+}
+
+/*
+ * The content filter config, from the record in POSTS if there is one so that it can be changed
+ * without a deploy, otherwise from the CONTENT_FILTERS var. No filters run without either, or when
+ * the config is invalid, which is logged rather than failing every new post
+ * */
+pub async fn get_filter_config<E: Backend>(env: &E) -> Result<FilterConfig> {
+    let config = match env.store("POSTS")?.get(FILTER_CONFIG_KEY).await? {
+        Some(config) => config,
+        None => match env.var("CONTENT_FILTERS") {
+            Ok(config) => config,
+            Err(_) => return Ok(FilterConfig::default()),
+        },
+    };
+    match serde_json::from_str(&config) {
+        Ok(config) => Ok(config),
+        Err(error) => {
+            utils::log(&format!(
+                "Ignoring invalid content filter config: {}",
+                error
+            ));
+            Ok(FilterConfig::default())
+        }
+    }
+}
+
+const FILTER_CONFIG_KEY: &str = "config:filters";
+
+/*
+ * Locks share the POSTS namespace the same way revisions do
+ * */
+const LOCK_PREFIX: &str = "lock:";
+
+/*
+ * Remove a post completely, only possible once nothing replies to it.
+ * Returns false if the post has replies
+ * */
+pub async fn purge_post<E: Backend>(env: &E, post_id: &str) -> Result<bool> {
+    let kv = env.store("POSTS")?;
+    if count_replies(&kv, post_id).await? > 0 {
+        return Ok(false);
+    }
+
+    delete_post(env, post_id).await?;
+    for key in kv.list(get_vote_prefix(post_id).as_str()).await? {
+        kv.delete(key.as_str()).await?;
+    }
+    kv.delete(&get_prefix(post_id, 0)).await?;
+    // Otherwise a post made later with the same id would start out locked and reported
+    set_locked(env, post_id, false).await?;
+    report::clear_reports(env, post_id).await?;
+
+    if let Some(parent_id) = get_parent_id(post_id) {
+        update_reply_count(&kv, parent_id, -1).await?;
+    }
+    Ok(true)
+}
+
+/*
+ * Set a user's vote on a post to 1 or -1, or remove it with 0. The post's score changes by the
+ * difference from their previous vote, so voting twice the same way counts once
+ * */
+pub async fn vote_post<E: Backend>(env: &E, post_id: &str, user_id: &str, vote: i8) -> Result<()> {
+    let kv = env.store("POSTS")?;
+    let mut post = match get_post(&kv, post_id).await? {
+        Some(post) => post,
+        None => {
+            return Err(Error::RustError(String::from(
+                "Cannot vote on a missing post",
+            )))
+        }
+    };
+
+    let previous = get_vote(env, post_id, user_id).await?;
+    let key = get_vote_key(post_id, user_id);
+    match vote.signum() {
+        0 => kv.delete(key.as_str()).await?,
+        vote => kv.put(key.as_str(), vote.to_string().as_str()).await?,
+    }
+
+    post.score += (vote.signum() - previous) as i64;
+    put_post(&kv, post_id, &post).await
+}
+
+/*
+ * The user's current vote on a post, 0 if they haven't voted
+ * */
+pub async fn get_vote<E: Backend>(env: &E, post_id: &str, user_id: &str) -> Result<i8> {
+    let vote = env
+        .store("POSTS")?
+        .get(get_vote_key(post_id, user_id).as_str())
+        .await?;
+    Ok(vote.and_then(|vote| vote.parse().ok()).unwrap_or(0))
+}
+
+/*
+ * Votes share the POSTS namespace the same way revisions do
+ * */
+fn get_vote_prefix(post_id: &str) -> String {
+    format!("vote:{}:", post_id)
+}
+
+fn get_vote_key(post_id: &str, user_id: &str) -> String {
+    format!("{}{}", get_vote_prefix(post_id), user_id)
+}
+
+/*
+ * Point a page of posts and votes that still refer to a user by email at their user id instead,
+ * returning how many were changed and the cursor for the next page. Run after
+ * user::migrate_legacy_users, references to accounts that haven't been migrated are left alone
+ * */
+pub async fn migrate_post_users<E: Backend>(
+    env: &E,
+    cursor: Option<&str>,
+    limit: u64,
+) -> Result<(usize, Option<String>)> {
+    let kv = env.store("POSTS")?;
+    let page = kv.list_page("", cursor, limit).await?;
+    let mut user_ids: HashMap<String, Option<String>> = HashMap::new();
+    let mut migrated = 0;
+    for key in page.keys {
+        let (post_id, legacy_id) = match key.name.strip_prefix("vote:") {
+            Some(vote) => match vote.rsplit_once(':') {
+                Some((post_id, voter)) => (post_id.to_string(), voter.to_string()),
+                None => continue,
+            },
+            // Posts are padded to the full key length, anything else is e.g. a revision, lock,
+            // report or config record
+            None if key.name.len() == 512 && key.name.starts_with(' ') => {
+                let post_id = key.name.trim_start();
+                match get_post(&kv, post_id).await? {
+                    Some(post) => (post_id.to_string(), post.user),
+                    None => continue,
+                }
+            }
+            None => continue,
+        };
+        if !user::is_legacy_id(&legacy_id) {
+            continue;
+        }
+        if !user_ids.contains_key(&legacy_id) {
+            let user = user::get_user(env, &legacy_id).await?;
+            user_ids.insert(
+                legacy_id.to_string(),
+                user.map(|user| user.user_id)
+                    .filter(|user_id| !user::is_legacy_id(user_id)),
+            );
+        }
+        let user_id = match &user_ids[&legacy_id] {
+            Some(user_id) => user_id,
+            None => continue,
+        };
+
+        if key.name.starts_with("vote:") {
+            // Moved through vote_post so the score stays right if they have since voted again
+            let vote = get_vote(env, &post_id, &legacy_id).await?;
+            vote_post(env, &post_id, &legacy_id, 0).await?;
+            if get_vote(env, &post_id, user_id).await? == 0 {
+                vote_post(env, &post_id, user_id, vote).await?;
+            }
+        } else if let Some(mut post) = get_post(&kv, &post_id).await? {
+            post.user = user_id.to_string();
+            put_post(&kv, &post_id, &post).await?;
+        }
+        migrated += 1;
+    }
+    Ok((migrated, page.cursor))
+}
+
+/*
+ * The post this post is a reply to, None for the root post
+ * */
+pub fn get_parent_id(post_id: &str) -> Option<&str> {
+    let length = post_id.chars().count();
+    if length > 0 {
+        Some(&post_id[..length - 1])
+    } else {
+        None
+    }
+}
+
+async fn get_post<S: Store>(kv: &S, post_id: &str) -> Result<Option<post_obj::Post>> {
+    match kv.get(get_prefix(post_id, 0).as_str()).await? {
+        Some(data) => Ok(Some(serde_json::from_str(data.as_str())?)),
+        None => Ok(None),
+    }
+}
+
+async fn put_post<S: Store>(kv: &S, post_id: &str, post: &post_obj::Post) -> Result<()> {
+    let post_string = serde_json::to_string(post)?;
+    let metadata = serde_json::to_value(post_obj::PostMetadata::from(post))?;
+    kv.put_with_metadata(
+        get_prefix(post_id, 0).as_str(),
+        post_string.as_str(),
+        &metadata,
+    )
+    .await
+}
+
+async fn count_replies<S: Store>(kv: &S, post_id: &str) -> Result<u32> {
+    let keys = kv.list(get_prefix(post_id, 1).as_str()).await?;
+    Ok(keys
+        .iter()
+        .filter(|key| key.rfind(|c: char| !c.is_whitespace()).is_some())
+        .count() as u32)
+}
+
+async fn update_reply_count<S: Store>(kv: &S, post_id: &str, change: i64) -> Result<()> {
+    if let Some(mut post) = get_post(kv, post_id).await? {
+        if post.version < 1 {
+            // Posts from before versioning have no reply count, so count the replies instead
+            post.reply_count = count_replies(kv, post_id).await?;
+            post.version = post_obj::POST_SCHEMA_VERSION;
+        } else {
+            post.reply_count = (post.reply_count as i64 + change).max(0) as u32;
+        }
+        put_post(kv, post_id, &post).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use crate::post_obj::SortOrder;
+    use futures::executor::block_on;
+
+    fn test_user() -> user_obj::User {
+        user_obj::User {
+            account: user_obj::UserAccount {
+                email: "test@example.com".to_string(),
+                hash: String::new(),
+                username: "test".to_string(),
+                status: user_obj::AccountStatus::Active,
+                role: user_obj::Role::User,
+                ban: None,
+            },
+            user_id: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn prefix_is_right_justified() {
+        let prefix = get_prefix("ab", 0);
+        assert_eq!(prefix.len(), 512);
+        assert!(prefix.ends_with(" ab"));
+        assert_eq!(get_prefix("ab", 1).len(), 511);
+    }
+
+    #[test]
+    fn post_and_get_content() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            assert!(get_content(&env, "a").await.unwrap().is_none());
+            post_content(&env, "a", "<b>hi</b>", test_user())
+                .await
+                .unwrap();
+            let post = get_content(&env, "a").await.unwrap().unwrap();
+            assert_eq!(post.title, "a");
+            assert_eq!(post.post.user, "test");
+            assert_eq!(post.post.content, "&lt;b&gt;hi&lt;/b&gt;");
+        });
+    }
+
+    #[test]
+    fn replies_are_direct_children_only() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "a", "", test_user()).await.unwrap();
+            post_content(&env, "ab", "", test_user()).await.unwrap();
+            post_content(&env, "ac", "", test_user()).await.unwrap();
+            post_content(&env, "abc", "", test_user()).await.unwrap();
+            post_content(&env, "b", "", test_user()).await.unwrap();
+
+            let titles = get_replies_page(&env, "a", SortOrder::Alphabetical, None, 50)
+                .await
+                .unwrap()
+                .0
+                .into_iter()
+                .map(|post| post.title)
+                .collect::<Vec<_>>();
+            assert_eq!(titles, vec!["ab", "ac"]);
+        });
+    }
+
+    #[test]
+    fn delete_leaves_tombstone() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "a", "content", test_user())
+                .await
+                .unwrap();
+            post_content(&env, "ab", "", test_user()).await.unwrap();
+            edit_post(&env, "a", "edited").await.unwrap();
+            delete_post(&env, "a").await.unwrap();
+
+            let post = get_content(&env, "a").await.unwrap().unwrap();
+            assert!(post.post.deleted);
+            assert!(post.user.is_none());
+            assert_eq!(post.post.content, "");
+            assert_eq!(post.post.reply_count, 1);
+            assert!(get_revisions(&env, "a").await.unwrap().is_empty());
+            assert_eq!(
+                get_replies_page(&env, "a", SortOrder::Alphabetical, None, 50)
+                    .await
+                    .unwrap()
+                    .0
+                    .len(),
+                1
+            );
+        });
+    }
+
+    #[test]
+    fn purge_only_without_replies() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "a", "", test_user()).await.unwrap();
+            post_content(&env, "ab", "", test_user()).await.unwrap();
+
+            assert!(!purge_post(&env, "a").await.unwrap());
+            assert!(get_content(&env, "a").await.unwrap().is_some());
+
+            set_locked(&env, "ab", true).await.unwrap();
+            report::report_post(&env, "ab", "x", "spam").await.unwrap();
+            assert!(purge_post(&env, "ab").await.unwrap());
+            assert!(get_content(&env, "ab").await.unwrap().is_none());
+            assert_eq!(get_lock(&env, "ab").await.unwrap(), None);
+            assert!(report::get_reports(&env, "ab").await.unwrap().is_none());
+            let post = get_content(&env, "a").await.unwrap().unwrap();
+            assert_eq!(post.post.reply_count, 0);
+        });
+    }
+
+    #[test]
+    fn edit_keeps_revisions() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "a", "first", test_user()).await.unwrap();
+            post_content(&env, "a0", "other", test_user())
+                .await
+                .unwrap();
+            edit_post(&env, "a", "second").await.unwrap();
+            env.advance(1000);
+            edit_post(&env, "a", "<third>").await.unwrap();
+
+            let post = get_content(&env, "a").await.unwrap().unwrap();
+            assert_eq!(post.post.content, "&lt;third&gt;");
+            assert_eq!(post.post.user, "test");
+
+            let revisions = get_revisions(&env, "a").await.unwrap();
+            let contents = revisions
+                .iter()
+                .map(|revision| revision.content.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(contents, vec!["first", "second"]);
+            assert!(revisions[0].timestamp < revisions[1].timestamp);
+            // e.g. a form submitted twice
+            edit_post(&env, "a", "fourth").await.unwrap();
+            edit_post(&env, "a", "fourth").await.unwrap();
+            assert_eq!(get_revisions(&env, "a").await.unwrap().len(), 4);
+
+            // Revisions are not replies
+            assert_eq!(
+                get_replies_page(&env, "", SortOrder::Alphabetical, None, 50)
+                    .await
+                    .unwrap()
+                    .0
+                    .len(),
+                1
+            );
+            assert!(get_revisions(&env, "a0").await.unwrap().is_empty());
+            assert!(edit_post(&env, "b", "").await.is_err());
+        });
+    }
+
+    #[test]
+    fn posts_have_metadata() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "a", "", test_user()).await.unwrap();
+            env.advance(1000);
+            post_content(&env, "ab", "", test_user()).await.unwrap();
+            post_content(&env, "ac", "", test_user()).await.unwrap();
+
+            let post = get_content(&env, "a").await.unwrap().unwrap().post;
+            assert_eq!(post.version, post_obj::POST_SCHEMA_VERSION);
+            assert_eq!(post.created_at, Some(env.now() - 1000));
+            assert_eq!(post.updated_at, None);
+            assert_eq!(post.reply_count, 2);
+
+            purge_post(&env, "ab").await.unwrap();
+            let post = get_content(&env, "a").await.unwrap().unwrap().post;
+            assert_eq!(post.reply_count, 1);
+
+            edit_post(&env, "a", "edited").await.unwrap();
+            let post = get_content(&env, "a").await.unwrap().unwrap().post;
+            assert_eq!(post.updated_at, Some(env.now()));
+        });
+    }
+
+    #[test]
+    fn legacy_posts_get_reply_counts() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let kv = env.store("POSTS").unwrap();
+            let legacy = r#"{"user":"test@example.com","content":""}"#;
+            for post_id in &["a", "ab", "ac"] {
+                kv.put(get_prefix(post_id, 0).as_str(), legacy)
+                    .await
+                    .unwrap();
+            }
+            post_content(&env, "ad", "", test_user()).await.unwrap();
+
+            let post = get_content(&env, "a").await.unwrap().unwrap().post;
+            assert_eq!(post.version, post_obj::POST_SCHEMA_VERSION);
+            assert_eq!(post.reply_count, 3);
+            assert_eq!(post.created_at, None);
+        });
+    }
+
+    #[test]
+    fn subtree_to_depth() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            for post_id in &["", "a", "b", "ab", "abc", "abcd", "ba"] {
+                post_content(&env, post_id, "", test_user()).await.unwrap();
+            }
+
+            let (tree, _) = get_subtree(&env, "", SortOrder::Alphabetical, None, 50, 3, 100)
+                .await
+                .unwrap();
+            assert_eq!(tree.len(), 2);
+            assert_eq!(tree[0].post.title, "a");
+            assert_eq!(tree[0].replies[0].post.title, "ab");
+            assert_eq!(tree[0].replies[0].replies[0].post.title, "abc");
+            assert!(tree[0].replies[0].replies[0].replies.is_empty());
+            assert_eq!(tree[1].replies[0].post.title, "ba");
+
+            let (tree, _) = get_subtree(&env, "", SortOrder::Alphabetical, None, 50, 1, 100)
+                .await
+                .unwrap();
+            assert!(tree.iter().all(|reply| reply.replies.is_empty()));
+        });
+    }
+
+    #[test]
+    fn subtree_respects_limit() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            for post_id in &["", "a", "b", "aa", "ab", "ba", "bb", "aaa"] {
+                post_content(&env, post_id, "", test_user()).await.unwrap();
+            }
+
+            // The first level uses 2 of the budget, leaving room to expand only "a"
+            let (tree, _) = get_subtree(&env, "", SortOrder::Alphabetical, None, 50, 5, 4)
+                .await
+                .unwrap();
+            assert_eq!(tree[0].replies.len(), 2);
+            assert!(tree[1].replies.is_empty());
+            assert!(tree[0].replies[0].replies.is_empty());
+        });
+    }
+
+    #[test]
+    fn replies_are_paginated() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            for post_id in &["", "a", "b", "c", "ca", "cb", "cc"] {
+                post_content(&env, post_id, "", test_user()).await.unwrap();
+            }
+
+            let (replies, cursor) = get_replies_page(&env, "c", SortOrder::Alphabetical, None, 2)
+                .await
+                .unwrap();
+            let titles = replies
+                .iter()
+                .map(|post| post.title.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(titles, vec!["ca", "cb"]);
+            let (replies, cursor) =
+                get_replies_page(&env, "c", SortOrder::Alphabetical, cursor.as_deref(), 2)
+                    .await
+                    .unwrap();
+            assert_eq!(replies[0].title, "cc");
+            assert!(cursor.is_none());
+
+            // Deeper levels only show the first page of their replies
+            let (tree, cursor) = get_subtree(&env, "", SortOrder::Alphabetical, None, 2, 2, 100)
+                .await
+                .unwrap();
+            assert_eq!(tree.len(), 2);
+            assert!(cursor.is_some());
+            let (tree, cursor) = get_subtree(
+                &env,
+                "",
+                SortOrder::Alphabetical,
+                cursor.as_deref(),
+                2,
+                2,
+                100,
+            )
+            .await
+            .unwrap();
+            assert_eq!(tree[0].post.title, "c");
+            assert_eq!(tree[0].replies.len(), 2);
+            assert!(cursor.is_none());
+        });
+    }
+
+    #[test]
+    fn replies_are_sorted() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            for post_id in &["", "b", "a", "c", "cc", "ca", "aa"] {
+                post_content(&env, post_id, "", test_user()).await.unwrap();
+                env.advance(1000);
+            }
+
+            let titles = |sort, limit, cursor: Option<String>| {
+                let env = &env;
+                async move {
+                    let (replies, cursor) =
+                        get_replies_page(env, "", sort, cursor.as_deref(), limit)
+                            .await
+                            .unwrap();
+                    let titles = replies
+                        .into_iter()
+                        .map(|post| post.title)
+                        .collect::<Vec<_>>();
+                    (titles, cursor)
+                }
+            };
+            assert_eq!(
+                titles(SortOrder::Alphabetical, 50, None).await.0,
+                vec!["a", "b", "c"]
+            );
+            assert_eq!(
+                titles(SortOrder::Newest, 50, None).await.0,
+                vec!["c", "a", "b"]
+            );
+            assert_eq!(
+                titles(SortOrder::Oldest, 50, None).await.0,
+                vec!["b", "a", "c"]
+            );
+            assert_eq!(
+                titles(SortOrder::MostReplies, 50, None).await.0,
+                vec!["c", "a", "b"]
+            );
+
+            let (page, cursor) = titles(SortOrder::Newest, 2, None).await;
+            assert_eq!(page, vec!["c", "a"]);
+            let (page, cursor) = titles(SortOrder::Newest, 2, cursor).await;
+            assert_eq!(page, vec!["b"]);
+            assert!(cursor.is_none());
+        });
+    }
+
+    #[test]
+    fn votes_count_once_per_user() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "", "", test_user()).await.unwrap();
+            post_content(&env, "a", "", test_user()).await.unwrap();
+            post_content(&env, "b", "", test_user()).await.unwrap();
+            let score = |post_id| {
+                let env = &env;
+                async move { get_content(env, post_id).await.unwrap().unwrap().post.score }
+            };
+
+            vote_post(&env, "a", "x@example.com", 1).await.unwrap();
+            vote_post(&env, "a", "x@example.com", 1).await.unwrap();
+            vote_post(&env, "a", "y@example.com", 1).await.unwrap();
+            assert_eq!(score("a").await, 2);
+            assert_eq!(get_vote(&env, "a", "x@example.com").await.unwrap(), 1);
+
+            // Changing a vote moves the score by the difference
+            vote_post(&env, "a", "x@example.com", -1).await.unwrap();
+            assert_eq!(score("a").await, 0);
+            vote_post(&env, "a", "x@example.com", 0).await.unwrap();
+            assert_eq!(score("a").await, 1);
+            assert_eq!(get_vote(&env, "a", "x@example.com").await.unwrap(), 0);
+
+            vote_post(&env, "b", "x@example.com", -1).await.unwrap();
+            let (replies, _) = get_replies_page(&env, "", SortOrder::Top, None, 50)
+                .await
+                .unwrap();
+            let titles = replies
+                .iter()
+                .map(|post| post.title.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(titles, vec!["a", "b"]);
+
+            assert!(vote_post(&env, "c", "x@example.com", 1).await.is_err());
+            assert!(purge_post(&env, "a").await.unwrap());
+            assert_eq!(get_vote(&env, "a", "y@example.com").await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn locks_cover_the_subtree() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            set_locked(&env, "ab", true).await.unwrap();
+            assert_eq!(get_lock(&env, "ab").await.unwrap(), Some("ab".to_string()));
+            assert_eq!(get_lock(&env, "abc").await.unwrap(), Some("ab".to_string()));
+            assert_eq!(get_lock(&env, "a").await.unwrap(), None);
+            assert_eq!(get_lock(&env, "b").await.unwrap(), None);
+            set_locked(&env, "ab", false).await.unwrap();
+            assert_eq!(get_lock(&env, "abc").await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn removed_posts_keep_their_content() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            post_content(&env, "a", "spam", test_user()).await.unwrap();
+            set_removed(&env, "a", true).await.unwrap();
+            let post = get_content(&env, "a").await.unwrap().unwrap();
+            assert!(post.post.removed);
+            assert_eq!(post.post.content, "spam");
+            set_removed(&env, "a", false).await.unwrap();
+            assert!(!get_content(&env, "a").await.unwrap().unwrap().post.removed);
+        });
+    }
+
+    #[test]
+    fn legacy_post_users_are_migrated() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let users_kv = env.store("USERS").unwrap();
+            users_kv
+                .put("a@example.com", r#"{"hash":"","username":"alice"}"#)
+                .await
+                .unwrap();
+            let legacy_user = || user_obj::User {
+                account: test_user().account,
+                user_id: "a@example.com".to_string(),
+            };
+            post_content(&env, "", "", legacy_user()).await.unwrap();
+            post_content(&env, "a", "", legacy_user()).await.unwrap();
+            post_content(&env, "b", "", test_user()).await.unwrap();
+            vote_post(&env, "a", "a@example.com", 1).await.unwrap();
+            vote_post(&env, "b", "a@example.com", -1).await.unwrap();
+            // Other records in the namespace are left alone, however long their keys
+            set_locked(&env, "a", true).await.unwrap();
+            report::report_post(&env, &"a".repeat(600), "x", "spam")
+                .await
+                .unwrap();
+            env.store("POSTS")
+                .unwrap()
+                .put(FILTER_CONFIG_KEY, "{}")
+                .await
+                .unwrap();
+
+            user::migrate_legacy_users(&env, None, 100).await.unwrap();
+            let user_id = user::get_user(&env, "a@example.com")
+                .await
+                .unwrap()
+                .unwrap()
+                .user_id;
+            // Voted again after their account moved, before their old vote did
+            vote_post(&env, "b", &user_id, -1).await.unwrap();
+
+            let mut migrated = 0;
+            let mut cursor = None;
+            loop {
+                let (count, next) = migrate_post_users(&env, cursor.as_deref(), 2)
+                    .await
+                    .unwrap();
+                migrated += count;
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            assert_eq!(migrated, 4);
+
+            let post = get_content(&env, "a").await.unwrap().unwrap();
+            assert_eq!(post.post.user, user_id);
+            assert_eq!(post.user.unwrap().account.username, "alice");
+            assert_eq!(post.post.score, 1);
+            assert_eq!(get_vote(&env, "a", &user_id).await.unwrap(), 1);
+            assert_eq!(get_vote(&env, "a", "a@example.com").await.unwrap(), 0);
+            let post = get_content(&env, "b").await.unwrap().unwrap();
+            assert_eq!(post.post.user, "test");
+            assert_eq!(post.post.score, -1);
+        });
+    }
+
+    #[test]
+    fn invalid_filter_config_runs_no_filters() {
+        let mut env = MemoryBackend::new();
+        env.set_var("CONTENT_FILTERS", "{\"banned_words\": [\"typo\"]}");
+        block_on(async {
+            assert!(get_filter_config(&env).await.unwrap().filters().is_empty());
+            let kv = env.store("POSTS").unwrap();
+            kv.put(FILTER_CONFIG_KEY, "{\"max_links\": {\"max\": 1}}")
+                .await
+                .unwrap();
+            assert_eq!(get_filter_config(&env).await.unwrap().filters().len(), 1);
+            kv.put(FILTER_CONFIG_KEY, "{\"max_links\": 1")
+                .await
+                .unwrap();
+            assert!(get_filter_config(&env).await.unwrap().filters().is_empty());
+        });
+    }
+}
@@ -0,0 +1,167 @@
+use crate::db::post::set_removed;
+use crate::db::store::{Backend, Store, MAX_LIST_LIMIT};
+use crate::report_obj::{PostReports, Report, ReportMetadata};
+use std::cmp::Reverse;
+use worker::*;
+
+/*
+ * Report a post, replacing any earlier report by the same user. Once the number of users
+ * reporting it reaches the REPORT_HIDE_THRESHOLD var the post is removed until a moderator
+ * reviews it, returns true if this report hid it
+ * */
+pub async fn report_post<E: Backend>(
+    env: &E,
+    post_id: &str,
+    user_id: &str,
+    reason: &str,
+) -> Result<bool> {
+    let mut reports = get_reports(env, post_id).await?.unwrap_or_default();
+    reports.reports.retain(|report| report.user != user_id);
+    reports.reports.push(Report {
+        user: user_id.to_string(),
+        reason: reason.to_string(),
+        timestamp: env.now(),
+    });
+
+    let threshold = env
+        .var("REPORT_HIDE_THRESHOLD")
+        .ok()
+        .and_then(|threshold| threshold.parse::<usize>().ok());
+    let hide = !reports.hidden
+        && matches!(threshold, Some(threshold) if reports.reports.len() >= threshold);
+    if hide {
+        set_removed(env, post_id, true).await?;
+        reports.hidden = true;
+    }
+    put_reports(env, post_id, &reports).await?;
+    Ok(hide)
+}
+
+pub async fn get_reports<E: Backend>(env: &E, post_id: &str) -> Result<Option<PostReports>> {
+    let kv = env.store("POSTS")?;
+    match kv.get(get_report_key(post_id).as_str()).await? {
+        Some(reports) => Ok(Some(serde_json::from_str(&reports)?)),
+        None => Ok(None),
+    }
+}
+
+/*
+ * Close the reports on a post once a moderator has dealt with them, returning them
+ * */
+pub async fn clear_reports<E: Backend>(env: &E, post_id: &str) -> Result<Option<PostReports>> {
+    let reports = get_reports(env, post_id).await?;
+    if reports.is_some() {
+        env.store("POSTS")?
+            .delete(get_report_key(post_id).as_str())
+            .await?;
+    }
+    Ok(reports)
+}
+
+/*
+ * Posts with open reports, those reported by the most users first, then the most recent
+ * */
+pub async fn list_reported<E: Backend>(env: &E, limit: usize) -> Result<Vec<String>> {
+    let kv = env.store("POSTS")?;
+    let mut reported = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = kv
+            .list_page(REPORT_PREFIX, cursor.as_deref(), MAX_LIST_LIMIT)
+            .await?;
+        reported.extend(page.keys.into_iter().map(|key| {
+            let metadata: ReportMetadata = key
+                .metadata
+                .and_then(|metadata| serde_json::from_value(metadata).ok())
+                .unwrap_or_default();
+            (key.name[REPORT_PREFIX.len()..].to_string(), metadata)
+        }));
+        match page.cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    reported.sort_by_key(|(_, metadata)| (Reverse(metadata.count), Reverse(metadata.latest)));
+    Ok(reported
+        .into_iter()
+        .take(limit)
+        .map(|(post_id, _)| post_id)
+        .collect())
+}
+
+async fn put_reports<E: Backend>(env: &E, post_id: &str, reports: &PostReports) -> Result<()> {
+    env.store("POSTS")?
+        .put_with_metadata(
+            get_report_key(post_id).as_str(),
+            serde_json::to_string(reports)?.as_str(),
+            &serde_json::to_value(ReportMetadata::from(reports))?,
+        )
+        .await
+}
+
+/*
+ * Reports share the POSTS namespace the same way locks do
+ * */
+const REPORT_PREFIX: &str = "report:";
+
+fn get_report_key(post_id: &str) -> String {
+    format!("{}{}", REPORT_PREFIX, post_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use crate::db::post::{get_content, post_content};
+    use crate::user_obj;
+    use futures::executor::block_on;
+
+    #[test]
+    fn reports_hide_posts_at_the_threshold() {
+        let mut env = MemoryBackend::new();
+        env.set_var("REPORT_HIDE_THRESHOLD", "2");
+        block_on(async {
+            let author = user_obj::User {
+                account: user_obj::UserAccount {
+                    email: String::new(),
+                    hash: String::new(),
+                    username: "author".to_string(),
+                    status: user_obj::AccountStatus::Active,
+                    role: user_obj::Role::User,
+                    ban: None,
+                },
+                user_id: "author".to_string(),
+            };
+            post_content(&env, "a", "spam", author).await.unwrap();
+            assert!(!report_post(&env, "a", "x", "spam").await.unwrap());
+            assert!(!report_post(&env, "a", "x", "abuse").await.unwrap());
+            assert_eq!(
+                get_reports(&env, "a").await.unwrap().unwrap().reports.len(),
+                1
+            );
+            assert!(report_post(&env, "a", "y", "spam").await.unwrap());
+            assert!(get_content(&env, "a").await.unwrap().unwrap().post.removed);
+            assert!(!report_post(&env, "a", "z", "spam").await.unwrap());
+
+            let reports = clear_reports(&env, "a").await.unwrap().unwrap();
+            assert!(reports.hidden);
+            assert_eq!(reports.reports.len(), 3);
+            assert!(get_reports(&env, "a").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn most_reported_are_listed_first() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            report_post(&env, "a", "x", "spam").await.unwrap();
+            env.advance(1);
+            report_post(&env, "b", "x", "spam").await.unwrap();
+            report_post(&env, "b", "y", "spam").await.unwrap();
+            env.advance(1);
+            report_post(&env, "c", "x", "spam").await.unwrap();
+            assert_eq!(list_reported(&env, 10).await.unwrap(), ["b", "c", "a"]);
+            assert_eq!(list_reported(&env, 1).await.unwrap(), ["b"]);
+        });
+    }
+}
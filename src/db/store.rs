@@ -0,0 +1,158 @@
+use crate::mail::{EnvMailer, LogMailer, MailChannelsMailer, MailSender};
+use async_trait::async_trait;
+use serde_json::Value;
+use worker::*;
+use worker_kv::KvStore;
+
+/*
+ * A key value store, e.g. a single Workers KV namespace
+ * */
+#[async_trait(?Send)]
+pub trait Store {
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+    async fn put(&self, key: &str, value: &str) -> Result<()>;
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: u64) -> Result<()>;
+    /*
+     * Metadata is returned alongside the key when listing, so it can be read without a get per key
+     * */
+    async fn put_with_metadata(&self, key: &str, value: &str, metadata: &Value) -> Result<()>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    /*
+     * A single page of keys starting with prefix, in lexical order
+     * */
+    async fn list_page(&self, prefix: &str, cursor: Option<&str>, limit: u64) -> Result<ListPage>;
+
+    /*
+     * Every key starting with prefix, following the cursor until the listing is complete
+     * */
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self
+                .list_page(prefix, cursor.as_deref(), MAX_LIST_LIMIT)
+                .await?;
+            keys.extend(page.keys.into_iter().map(|key| key.name));
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(keys),
+            }
+        }
+    }
+}
+
+/*
+ * Most keys KV will return from a single list call
+ * */
+pub const MAX_LIST_LIMIT: u64 = 1000;
+
+pub struct ListKey {
+    pub name: String,
+    pub metadata: Option<Value>,
+}
+
+pub struct ListPage {
+    pub keys: Vec<ListKey>,
+    /*
+     * Cursor for the next page, None once the listing is complete
+     * */
+    pub cursor: Option<String>,
+}
+
+/*
+ * Provides the stores and variables the db layer needs, e.g. the worker Env
+ * */
+pub trait Backend {
+    type Store: Store;
+    type Mailer: MailSender;
+
+    fn store(&self, binding: &str) -> Result<Self::Store>;
+    fn var(&self, name: &str) -> Result<String>;
+    fn mailer(&self) -> Self::Mailer;
+    /*
+     * Current time in milliseconds since the unix epoch
+     * */
+    fn now(&self) -> u64;
+}
+
+#[async_trait(?Send)]
+impl Store for KvStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(KvStore::get(self, key)
+            .await?
+            .map(|value| value.as_string()))
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        KvStore::put(self, key, value)?.execute().await?;
+        Ok(())
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: u64) -> Result<()> {
+        KvStore::put(self, key, value)?
+            .expiration_ttl(ttl)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn put_with_metadata(&self, key: &str, value: &str, metadata: &Value) -> Result<()> {
+        KvStore::put(self, key, value)?
+            .metadata(metadata)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        KvStore::delete(self, key).await?;
+        Ok(())
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<&str>, limit: u64) -> Result<ListPage> {
+        let mut builder = KvStore::list(self).prefix(prefix.to_string()).limit(limit);
+        if let Some(cursor) = cursor {
+            builder = builder.cursor(cursor.to_string());
+        }
+        let response = builder.execute().await?;
+        let list_complete = response.list_complete;
+        Ok(ListPage {
+            keys: response
+                .keys
+                .into_iter()
+                .map(|key| ListKey {
+                    name: key.name,
+                    metadata: key.metadata,
+                })
+                .collect(),
+            cursor: response.cursor.filter(|_| !list_complete),
+        })
+    }
+}
+
+impl Backend for Env {
+    type Store = KvStore;
+    type Mailer = EnvMailer;
+
+    fn store(&self, binding: &str) -> Result<KvStore> {
+        self.kv(binding)
+    }
+
+    fn var(&self, name: &str) -> Result<String> {
+        Ok(Env::var(self, name)?.to_string())
+    }
+
+    fn mailer(&self) -> EnvMailer {
+        match Backend::var(self, "MAIL_LOG").as_deref() {
+            Ok("true") => EnvMailer::Log(LogMailer),
+            _ => EnvMailer::MailChannels(MailChannelsMailer {
+                from: Backend::var(self, "MAIL_FROM").ok(),
+                api_key: Backend::var(self, "MAILCHANNELS_API_KEY").ok(),
+            }),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        Date::now().as_millis()
+    }
+}
@@ -0,0 +1,246 @@
+use crate::db::store::{Backend, Store};
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/*
+ * How quickly something is locked out after repeated failures
+ * */
+pub struct Backoff {
+    /*
+     * Failures in a row before the key is first locked out
+     * */
+    pub max_failures: u32,
+    /*
+     * First lockout in seconds, doubling with each failure after that
+     * */
+    pub lockout: u64,
+    pub max_lockout: u64,
+    /*
+     * Seconds without a failure before the count starts again
+     * */
+    pub window: u64,
+}
+
+/*
+ * Guessing one account's password
+ * */
+pub const ACCOUNT_BACKOFF: Backoff = Backoff {
+    max_failures: 5,
+    lockout: 30,
+    max_lockout: 60 * 60,
+    window: 60 * 60,
+};
+
+/*
+ * Trying many accounts from one address, which may be shared by several people
+ * */
+pub const IP_BACKOFF: Backoff = Backoff {
+    max_failures: 20,
+    lockout: 30,
+    max_lockout: 60 * 60,
+    window: 60 * 60,
+};
+
+#[derive(Serialize, Deserialize, Default)]
+struct Failures {
+    count: u32,
+    locked_until: u64,
+}
+
+/*
+ * Seconds until the key can be tried again, None if it isn't locked out
+ * */
+pub async fn lockout<E: Backend>(env: &E, key: &str) -> Result<Option<u64>> {
+    let failures = get_failures(env, key).await?;
+    let now = env.now();
+    match failures.locked_until > now {
+        // Rounded up, so that trying again after that long always works
+        true => Ok(Some((failures.locked_until - now).div_ceil(1000))),
+        false => Ok(None),
+    }
+}
+
+pub async fn record_failure<E: Backend>(env: &E, key: &str, backoff: &Backoff) -> Result<()> {
+    let mut failures = get_failures(env, key).await?;
+    failures.count += 1;
+    let mut ttl = backoff.window;
+    if failures.count >= backoff.max_failures {
+        let doublings = (failures.count - backoff.max_failures).min(32);
+        let lockout = backoff
+            .lockout
+            .saturating_mul(1 << doublings)
+            .min(backoff.max_lockout);
+        failures.locked_until = env.now() + lockout * 1000;
+        ttl = ttl.max(lockout);
+    }
+    // Kv only keeps keys for 60 seconds or more
+    env.store("SESSIONS")?
+        .put_with_ttl(
+            get_throttle_key(key).as_str(),
+            serde_json::to_string(&failures)?.as_str(),
+            ttl.max(60),
+        )
+        .await
+}
+
+pub async fn clear_failures<E: Backend>(env: &E, key: &str) -> Result<()> {
+    env.store("SESSIONS")?
+        .delete(get_throttle_key(key).as_str())
+        .await
+}
+
+async fn get_failures<E: Backend>(env: &E, key: &str) -> Result<Failures> {
+    match env
+        .store("SESSIONS")?
+        .get(get_throttle_key(key).as_str())
+        .await?
+    {
+        Some(data) => Ok(serde_json::from_str(data.as_str()).unwrap_or_default()),
+        None => Ok(Failures::default()),
+    }
+}
+
+/*
+ * At most count actions in each period of seconds
+ * */
+#[derive(Debug, PartialEq)]
+pub struct RateLimit {
+    pub count: u32,
+    pub period: u64,
+}
+
+/*
+ * Count an action against every limit on each of the keys, unless one of them has already been
+ * reached. Returns the seconds until the action is allowed again, None if it was allowed and
+ * counted. Each limit counts in fixed periods, so up to twice the count can happen across a boundary
+ * */
+pub async fn take<E: Backend>(env: &E, keys: &[(&str, &[RateLimit])]) -> Result<Option<u64>> {
+    let kv = env.store("SESSIONS")?;
+    let now = env.now() / 1000;
+    let mut counts = Vec::new();
+    let mut retry_after = None;
+    for (key, limits) in keys {
+        for limit in limits.iter() {
+            let window = now / limit.period;
+            let window_key = format!("{}:{}:{}", get_throttle_key(key), limit.period, window);
+            let count = match kv.get(window_key.as_str()).await? {
+                Some(count) => count.parse::<u32>().unwrap_or(0),
+                None => 0,
+            };
+            if count >= limit.count {
+                let window_end = (window + 1) * limit.period;
+                retry_after = retry_after.max(Some(window_end - now));
+            }
+            counts.push((window_key, count, limit.period));
+        }
+    }
+    if retry_after.is_some() {
+        return Ok(retry_after);
+    }
+
+    for (window_key, count, period) in counts {
+        kv.put_with_ttl(
+            window_key.as_str(),
+            (count + 1).to_string().as_str(),
+            period.max(60),
+        )
+        .await?;
+    }
+    Ok(None)
+}
+
+/*
+ * Counts share the SESSIONS namespace, as they are just as short lived. Kv is eventually
+ * consistent, so a burst of attempts spread over several locations can slip a few more through
+ * */
+fn get_throttle_key(key: &str) -> String {
+    format!("throttle:{}", key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use futures::executor::block_on;
+
+    const BACKOFF: Backoff = Backoff {
+        max_failures: 2,
+        lockout: 30,
+        max_lockout: 100,
+        window: 60 * 60,
+    };
+
+    #[test]
+    fn lockouts_double_up_to_the_limit() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            record_failure(&env, "a", &BACKOFF).await.unwrap();
+            assert_eq!(lockout(&env, "a").await.unwrap(), None);
+
+            let mut lockouts = Vec::new();
+            for _ in 0..4 {
+                record_failure(&env, "a", &BACKOFF).await.unwrap();
+                lockouts.push(lockout(&env, "a").await.unwrap().unwrap());
+            }
+            assert_eq!(lockouts, vec![30, 60, 100, 100]);
+            assert_eq!(lockout(&env, "b").await.unwrap(), None);
+
+            env.advance(100_000);
+            assert_eq!(lockout(&env, "a").await.unwrap(), None);
+            clear_failures(&env, "a").await.unwrap();
+            record_failure(&env, "a", &BACKOFF).await.unwrap();
+            assert_eq!(lockout(&env, "a").await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn rate_limits_reset_each_period() {
+        let env = MemoryBackend::new();
+        // Start on a day boundary so the periods line up with the test
+        let day = 60 * 60 * 24 * 1000;
+        env.advance(day - env.now() % day);
+        let limits = [
+            RateLimit {
+                count: 2,
+                period: 60,
+            },
+            RateLimit {
+                count: 3,
+                period: 60 * 60 * 24,
+            },
+        ];
+        block_on(async {
+            assert_eq!(take(&env, &[("a", &limits)]).await.unwrap(), None);
+            assert_eq!(take(&env, &[("a", &limits)]).await.unwrap(), None);
+            assert_eq!(take(&env, &[("a", &limits)]).await.unwrap(), Some(60));
+            assert_eq!(take(&env, &[("b", &limits)]).await.unwrap(), None);
+
+            env.advance(20_000);
+            assert_eq!(take(&env, &[("a", &limits)]).await.unwrap(), Some(40));
+            env.advance(40_000);
+            assert_eq!(take(&env, &[("a", &limits)]).await.unwrap(), None);
+            env.advance(60_000);
+            assert_eq!(
+                take(&env, &[("a", &limits)]).await.unwrap(),
+                Some(60 * 60 * 24 - 120)
+            );
+        });
+    }
+
+    #[test]
+    fn nothing_is_counted_when_one_key_is_limited() {
+        let env = MemoryBackend::new();
+        let limits = [RateLimit {
+            count: 1,
+            period: 60 * 60 * 24,
+        }];
+        block_on(async {
+            assert_eq!(take(&env, &[("a", &limits)]).await.unwrap(), None);
+            assert!(take(&env, &[("b", &limits), ("a", &limits)])
+                .await
+                .unwrap()
+                .is_some());
+            assert_eq!(take(&env, &[("b", &limits)]).await.unwrap(), None);
+        });
+    }
+}
@@ -1,70 +1,432 @@
 use crate::crypto_helpers;
+use crate::db::store::{Backend, Store};
+use crate::db::throttle;
 use crate::user_obj;
+use std::cmp::Reverse;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use uuid::Uuid;
 use worker::*;
 
-pub async fn create_session<S: AsRef<str>>(
-    env: &Env,
-    user_id: S,
+/*
+ * How long a new account has to be verified before it, and its verification link, expire
+ * */
+const VERIFICATION_EXPIRY: u64 = 60 * 60 * 24;
+/*
+ * How long a password reset link works for
+ * */
+const RESET_EXPIRY: u64 = 60 * 60;
+
+/*
+ * Reasons a login can be refused, shared by the html forms and the json api
+ * */
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    InvalidCredentials,
+    Unverified,
+    /*
+     * Seconds until logging in can be tried again
+     * */
+    TooManyAttempts(u64),
+    Banned(user_obj::Ban),
+}
+
+impl LoginError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            LoginError::InvalidCredentials => "Invalid Username or password",
+            LoginError::Unverified => "Check your email to verify your account before logging in",
+            LoginError::TooManyAttempts(_) => {
+                "Too many failed login attempts, wait a while and try again"
+            }
+            LoginError::Banned(_) => "This account has been banned",
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            LoginError::InvalidCredentials => 401,
+            LoginError::Unverified => 403,
+            LoginError::TooManyAttempts(_) => 429,
+            LoginError::Banned(_) => 403,
+        }
+    }
+}
+
+/*
+ * Reasons an account can't be created or changed, shared by the html forms and the json api
+ * */
+#[derive(Debug, PartialEq)]
+pub enum AccountError {
+    InvalidEmail,
+    EmailTaken,
+    UsernameTaken,
+    ReservedUsername,
+    InvalidUsername,
+    PasswordTooShort,
+    WrongPassword,
+}
+
+impl AccountError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            AccountError::InvalidEmail => "Error: that isn't a valid email address",
+            AccountError::EmailTaken => "Error: user already exists",
+            AccountError::UsernameTaken => "Error: that username is taken",
+            AccountError::ReservedUsername => "Error: that username is reserved",
+            AccountError::InvalidUsername => {
+                "Usernames must be 1 to 32 letters, numbers, dashes or underscores"
+            }
+            AccountError::PasswordTooShort => "Passwords must be at least 8 characters",
+            AccountError::WrongPassword => "Error: current password is incorrect",
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            AccountError::EmailTaken | AccountError::UsernameTaken => 409,
+            AccountError::WrongPassword => 403,
+            _ => 400,
+        }
+    }
+}
+
+/*
+ * Names that could be mistaken for the site or for the placeholders shown in place of a user
+ * */
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "anonymous",
+    "deleted",
+    "mod",
+    "moderator",
+    "null",
+    "root",
+    "staff",
+    "support",
+    "system",
+    "treply",
+];
+
+pub fn validate_username(username: &str) -> Option<AccountError> {
+    let valid_char = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+    match username.chars().count() {
+        1..=32 if username.chars().all(valid_char) => {
+            let skeleton = username_skeleton(username);
+            match RESERVED_USERNAMES
+                .iter()
+                .any(|reserved| username_skeleton(reserved) == skeleton)
+            {
+                true => Some(AccountError::ReservedUsername),
+                false => None,
+            }
+        }
+        _ => Some(AccountError::InvalidUsername),
+    }
+}
+
+/*
+ * What a username looks like, so that names which can't be told apart on the page share an index
+ * key. Compatibility forms and accents are removed, then lookalike letters from other scripts,
+ * digits and letter pairs are mapped to a single latin letter
+ * */
+fn username_skeleton(username: &str) -> String {
+    let skeleton = username
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'а' | 'α' => 'a',
+            'ь' => 'b',
+            'с' | 'ϲ' => 'c',
+            'ԁ' => 'd',
+            'е' | 'ё' => 'e',
+            'һ' => 'h',
+            'ј' => 'j',
+            'к' | 'κ' => 'k',
+            '1' | 'i' | 'і' | 'ι' | 'ӏ' => 'l',
+            'ո' => 'n',
+            '0' | 'о' | 'ο' => 'o',
+            'р' | 'ρ' => 'p',
+            'ѕ' => 's',
+            'υ' => 'u',
+            'ν' => 'v',
+            'ԝ' => 'w',
+            'х' | 'χ' => 'x',
+            'у' => 'y',
+            '-' => '_',
+            c => c,
+        })
+        .collect::<String>();
+    skeleton.replace("rn", "m").replace("vv", "w")
+}
+
+/*
+ * Only catches obvious mistakes, whether the address works is found out by the verification mail
+ * */
+pub fn validate_email(email: &str) -> Option<AccountError> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !domain.contains('@')
+                && email.len() <= 254
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    match valid {
+        true => None,
+        false => Some(AccountError::InvalidEmail),
+    }
+}
+
+pub fn validate_password(password: &str) -> Option<AccountError> {
+    match password.chars().count() {
+        0..=7 => Some(AccountError::PasswordTooShort),
+        _ => None,
+    }
+}
+
+pub async fn create_session<E: Backend, S: AsRef<str>>(
+    env: &E,
+    email: S,
     password: S,
-) -> Result<Option<String>> {
-    let user_id = user_id.as_ref();
+    client: &user_obj::Client,
+) -> Result<std::result::Result<String, LoginError>> {
     let password = password.as_ref();
+    let email = email.as_ref();
 
-    match get_user(env, user_id).await? {
-        None => Ok(None),
-        Some(user) => {
-            if crypto_helpers::verify_password(password, &user.account.hash) {
-                let session_id = Uuid::new_v4().to_simple().to_string();
-                update_session(env, user_id, &session_id).await?;
-
-                Ok(Some(session_id))
-            } else {
-                Ok(None)
+    // Checked before the password is, as verifying it is deliberately expensive
+    let account_key = get_login_throttle_key(email);
+    let ip_key = client.ip.as_ref().map(|ip| format!("login:ip:{}", ip));
+    let mut retry_after = throttle::lockout(env, &account_key).await?;
+    if let Some(ip_key) = &ip_key {
+        retry_after = retry_after.max(throttle::lockout(env, ip_key).await?);
+    }
+    if let Some(retry_after) = retry_after {
+        return Ok(Err(LoginError::TooManyAttempts(retry_after)));
+    }
+
+    let user = match get_user_by_email(env, email).await? {
+        Some(user) if crypto_helpers::verify_password(password, &user.account.hash) => user,
+        // Unknown emails count too, so that lockouts don't reveal which accounts exist
+        _ => {
+            throttle::record_failure(env, &account_key, &throttle::ACCOUNT_BACKOFF).await?;
+            if let Some(ip_key) = &ip_key {
+                throttle::record_failure(env, ip_key, &throttle::IP_BACKOFF).await?;
             }
+            return Ok(Err(LoginError::InvalidCredentials));
         }
+    };
+    throttle::clear_failures(env, &account_key).await?;
+
+    // Only checked once the password matches, so that it doesn't reveal which emails have
+    // pending accounts
+    if user.account.status == user_obj::AccountStatus::Pending {
+        return Ok(Err(LoginError::Unverified));
+    }
+    if let Some(ban) = user.active_ban(env.now()) {
+        return Ok(Err(LoginError::Banned(ban.clone())));
+    }
+    let mut user = user;
+    if user.account.role != user_obj::Role::Admin && is_admin_email(env, email) {
+        user.account.role = user_obj::Role::Admin;
+        put_account(env, &user.user_id, &user.account).await?;
+    }
+    claim_username(env, &user.account.username, &user.user_id, None).await?;
+    let session_id = Uuid::new_v4().to_simple().to_string();
+    let info = user_obj::SessionInfo {
+        created_at: Some(env.now()),
+        last_seen: env.now(),
+        client: client.clone(),
+    };
+    update_session(env, &user.user_id, &session_id, &info).await?;
+
+    Ok(Ok(session_id))
+}
+
+/*
+ * Listed in the ADMIN_EMAILS var, which is how the first admin is made
+ * */
+fn is_admin_email<E: Backend>(env: &E, email: &str) -> bool {
+    match env.var("ADMIN_EMAILS") {
+        Ok(emails) => emails
+            .split(',')
+            .map(str::trim)
+            .any(|admin| !admin.is_empty() && admin.eq_ignore_ascii_case(email.trim())),
+        Err(_) => false,
     }
 }
+
+/*
+ * Attempts are counted per email, whether or not it has an account
+ * */
+fn get_login_throttle_key(email: &str) -> String {
+    format!("login:email:{}", email.trim().to_lowercase())
+}
 /*
  * Write the session to the kv store with the correct expiry time
  * */
-async fn update_session<S: AsRef<str>, S2: AsRef<str>>(
-    env: &Env,
+async fn update_session<E: Backend, S: AsRef<str>, S2: AsRef<str>>(
+    env: &E,
     user_id: S,
     session_id: S2,
+    info: &user_obj::SessionInfo,
 ) -> Result<()> {
-    let sessions_kv = env.kv("SESSIONS")?;
-
-    let expiry: u64 = env
-        .var("SESSION_EXPIRY")?
-        .to_string()
-        .parse::<u64>()
-        .expect("Error: Could not parse expiry environment variable");
+    let sessions_kv = env.store("SESSIONS")?;
+    let expiry = session_expiry(env)?;
 
     sessions_kv
-        .put(session_id.as_ref(), user_id.as_ref())?
-        .expiration_ttl(expiry)
-        .execute()
+        .put_with_ttl(session_id.as_ref(), user_id.as_ref(), expiry)
+        .await?;
+    // Indexed by user as well, so that all of a user's sessions can be found
+    sessions_kv
+        .put_with_ttl(
+            get_user_session_key(user_id.as_ref(), session_id.as_ref()).as_str(),
+            serde_json::to_string(info)?.as_str(),
+            expiry,
+        )
         .await?;
 
     Ok(())
 }
 
-pub async fn delete_session<S: AsRef<str>>(env: &Env, session_id: S) -> Result<()> {
+/*
+ * How long a session lasts without being used, in seconds
+ * */
+pub fn session_expiry<E: Backend>(env: &E) -> Result<u64> {
+    env.var("SESSION_EXPIRY")?
+        .parse::<u64>()
+        .map_err(|_| Error::RustError("Could not parse SESSION_EXPIRY".to_string()))
+}
+
+pub async fn delete_session<E: Backend, S: AsRef<str>>(env: &E, session_id: S) -> Result<()> {
     let session_id = session_id.as_ref();
-    let sessions_kv = env.kv("SESSIONS")?;
+    let sessions_kv = env.store("SESSIONS")?;
+    if let Some(user_id) = sessions_kv.get(session_id).await? {
+        sessions_kv
+            .delete(get_user_session_key(&user_id, session_id).as_str())
+            .await?;
+    }
     sessions_kv.delete(session_id).await?;
     Ok(())
 }
 
-pub async fn get_user<S: AsRef<str>>(env: &Env, user_id: S) -> Result<Option<user_obj::User>> {
+/*
+ * Log a user out everywhere, except optionally the session they are using
+ * */
+pub async fn revoke_sessions<E: Backend>(
+    env: &E,
+    user_id: &str,
+    except: Option<&str>,
+) -> Result<()> {
+    let sessions_kv = env.store("SESSIONS")?;
+    let prefix = get_user_session_prefix(user_id);
+    for key in sessions_kv.list(prefix.as_str()).await? {
+        let session_id = &key[prefix.len()..];
+        if Some(session_id) == except {
+            continue;
+        }
+        sessions_kv.delete(session_id).await?;
+        sessions_kv.delete(key.as_str()).await?;
+    }
+    Ok(())
+}
+
+/*
+ * Every session a user is logged in with, most recently used first
+ * */
+pub async fn list_sessions<E: Backend>(env: &E, user_id: &str) -> Result<Vec<user_obj::Session>> {
+    let sessions_kv = env.store("SESSIONS")?;
+    let prefix = get_user_session_prefix(user_id);
+    let mut sessions = Vec::new();
+    for key in sessions_kv.list(prefix.as_str()).await? {
+        let info = match sessions_kv.get(key.as_str()).await? {
+            Some(data) => serde_json::from_str(data.as_str()).unwrap_or_default(),
+            None => continue,
+        };
+        sessions.push(user_obj::Session {
+            session_id: key[prefix.len()..].to_string(),
+            info,
+        });
+    }
+    sessions.sort_by_key(|session| Reverse(session.info.last_seen));
+    Ok(sessions)
+}
+
+/*
+ * Log out one of a user's sessions by its public id, the session id itself is never shown.
+ * Returns false if the user has no such session
+ * */
+pub async fn revoke_session<E: Backend>(env: &E, user_id: &str, public_id: &str) -> Result<bool> {
+    let sessions_kv = env.store("SESSIONS")?;
+    let prefix = get_user_session_prefix(user_id);
+    for key in sessions_kv.list(prefix.as_str()).await? {
+        let session_id = &key[prefix.len()..];
+        if crypto_helpers::public_id(session_id) == public_id {
+            sessions_kv.delete(session_id).await?;
+            sessions_kv.delete(key.as_str()).await?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/*
+ * Session ids are plain uuids, so the index can share the SESSIONS namespace
+ * */
+fn get_user_session_prefix(user_id: &str) -> String {
+    format!("user:{}:", user_id)
+}
+
+fn get_user_session_key(user_id: &str, session_id: &str) -> String {
+    format!("{}{}", get_user_session_prefix(user_id), session_id)
+}
+
+/*
+ * Accounts from before user ids were stored under their email, which is what their posts, votes
+ * and sessions refer to until they are migrated
+ * */
+pub fn is_legacy_id(user_id: &str) -> bool {
+    user_id.contains('@') && !user_id.contains(':')
+}
+
+pub async fn get_user<E: Backend, S: AsRef<str>>(
+    env: &E,
+    user_id: S,
+) -> Result<Option<user_obj::User>> {
     let user_id = user_id.as_ref();
-    let users_kv = env.kv("USERS")?;
+    match get_account(env, user_id).await? {
+        // A legacy id whose account has already been migrated
+        None if is_legacy_id(user_id) => {
+            let users_kv = env.store("USERS")?;
+            match users_kv.get(get_email_key(user_id).as_str()).await? {
+                Some(user_id) => get_account(env, &user_id).await,
+                None => Ok(None),
+            }
+        }
+        user => Ok(user),
+    }
+}
+
+pub async fn get_user_by_email<E: Backend>(env: &E, email: &str) -> Result<Option<user_obj::User>> {
+    let users_kv = env.store("USERS")?;
+    match users_kv.get(get_email_key(email).as_str()).await? {
+        Some(user_id) => get_account(env, &user_id).await,
+        None if is_legacy_id(email) => get_account(env, email).await,
+        None => Ok(None),
+    }
+}
+
+async fn get_account<E: Backend>(env: &E, user_id: &str) -> Result<Option<user_obj::User>> {
+    let users_kv = env.store("USERS")?;
     let user_data = users_kv.get(user_id).await?;
     Ok(match user_data {
         Some(data) => {
-            let deserialised: user_obj::UserAccount =
-                serde_json::from_str(data.as_string().as_str())?;
+            let deserialised: user_obj::UserAccount = serde_json::from_str(data.as_str())?;
             Some(user_obj::User {
                 account: deserialised,
                 user_id: user_id.to_string(),
@@ -74,50 +436,969 @@ pub async fn get_user<S: AsRef<str>>(env: &Env, user_id: S) -> Result<Option<use
     })
 }
 
-pub async fn get_session<S: AsRef<str>>(
-    env: &Env,
+pub async fn get_session<E: Backend, S: AsRef<str>>(
+    env: &E,
     session_id: S,
 ) -> Result<Option<user_obj::User>> {
     let session_id = session_id.as_ref();
 
-    let sessions_kv = env.kv("SESSIONS")?;
+    let sessions_kv = env.store("SESSIONS")?;
 
-    match sessions_kv.get(session_id).await? {
-        None => Ok(None),
-        Some(user_id) => {
-            let user_id = user_id.as_string();
-            update_session(env, &user_id, session_id).await?;
-            get_user(env, &user_id).await
-        }
+    let user = match sessions_kv.get(session_id).await? {
+        None => return Ok(None),
+        Some(user_id) => get_user(env, &user_id).await?,
+    };
+    // Only real sessions are refreshed, e.g. not keys from the user index
+    if let Some(user) = &user {
+        let mut info: user_obj::SessionInfo = match sessions_kv
+            .get(get_user_session_key(&user.user_id, session_id).as_str())
+            .await?
+        {
+            Some(data) => serde_json::from_str(data.as_str()).unwrap_or_default(),
+            None => Default::default(),
+        };
+        info.last_seen = env.now();
+        update_session(env, &user.user_id, session_id, &info).await?;
     }
+    Ok(user)
 }
 
-pub async fn create_user<S: AsRef<str>>(
-    env: &Env,
-    user_id: S,
+/*
+ * Create a pending account, returning the token that verifies it.
+ * Pending accounts and their usernames expire along with their token, so neither can be held by
+ * someone who doesn't own the address
+ * */
+pub async fn create_user<E: Backend, S: AsRef<str>>(
+    env: &E,
+    email: S,
     username: S,
     password: S,
-) -> Result<Option<String>> {
+) -> Result<std::result::Result<String, AccountError>> {
     let username = username.as_ref();
     let password = password.as_ref();
-    let user_id = user_id.as_ref();
+    let email = email.as_ref().trim();
+
+    if let Some(error) = validate_email(email)
+        .or_else(|| validate_username(username))
+        .or_else(|| validate_password(password))
+    {
+        return Ok(Err(error));
+    }
+    match get_user_by_email(env, email).await? {
+        // Never verified, e.g. the mail couldn't be sent, so registering again starts over
+        Some(user) if user.account.status == user_obj::AccountStatus::Pending => {
+            delete_pending_user(env, &user).await?
+        }
+        Some(_) => return Ok(Err(AccountError::EmailTaken)),
+        None => {}
+    }
+    let user_id = Uuid::new_v4().to_simple().to_string();
+    if !claim_username(env, username, &user_id, Some(VERIFICATION_EXPIRY)).await? {
+        return Ok(Err(AccountError::UsernameTaken));
+    }
 
     let hash = crypto_helpers::hash_password(password);
     let acc = user_obj::UserAccount {
+        email: email.to_string(),
         hash: hash.to_string(),
         username: username.to_string(),
+        status: user_obj::AccountStatus::Pending,
+        role: user_obj::Role::User,
+        ban: None,
+    };
+    put_account_with_ttl(env, &user_id, &acc, Some(VERIFICATION_EXPIRY)).await?;
+
+    Ok(Ok(create_token(
+        env,
+        "verify",
+        &user_id,
+        VERIFICATION_EXPIRY,
+    )
+    .await?))
+}
+
+/*
+ * Remove an account that was never verified along with its username claim, its verification link
+ * stops working as the account is gone
+ * */
+async fn delete_pending_user<E: Backend>(env: &E, user: &user_obj::User) -> Result<()> {
+    let users_kv = env.store("USERS")?;
+    let username_key = get_username_key(&user.account.username);
+    if users_kv.get(username_key.as_str()).await?.as_deref() == Some(user.user_id.as_str()) {
+        users_kv.delete(username_key.as_str()).await?;
+    }
+    if !user.account.email.is_empty() {
+        users_kv
+            .delete(get_email_key(&user.account.email).as_str())
+            .await?;
+    }
+    users_kv.delete(&user.user_id).await
+}
+
+/*
+ * Activate the account a verification token was issued for, each token works once.
+ * Returns the user id, None if the token is invalid or has expired
+ * */
+pub async fn verify_user<E: Backend>(env: &E, token: &str) -> Result<Option<String>> {
+    let user_id = match use_token(env, "verify", token).await? {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+
+    match get_user(env, &user_id).await? {
+        Some(mut user) => {
+            user.account.status = user_obj::AccountStatus::Active;
+            // Stored without a ttl now that the account is active
+            put_account(env, &user.user_id, &user.account).await?;
+            claim_username(env, &user.account.username, &user.user_id, None).await?;
+            Ok(Some(user.user_id))
+        }
+        None => Ok(None),
+    }
+}
+
+/*
+ * Token for resetting the password of an active account, None if there is no such account
+ * */
+pub async fn create_password_reset<E: Backend>(env: &E, email: &str) -> Result<Option<String>> {
+    match get_user_by_email(env, email).await? {
+        Some(user) if user.account.status == user_obj::AccountStatus::Active => Ok(Some(
+            create_token(env, "reset", &user.user_id, RESET_EXPIRY).await?,
+        )),
+        _ => Ok(None),
+    }
+}
+
+/*
+ * Set a new password with a reset token, logging the user out everywhere.
+ * Returns false if the token is invalid or has expired
+ * */
+pub async fn reset_password<E: Backend>(
+    env: &E,
+    token: &str,
+    password: &str,
+) -> Result<std::result::Result<bool, AccountError>> {
+    // Checked first so that the token isn't used up
+    if let Some(error) = validate_password(password) {
+        return Ok(Err(error));
+    }
+    let user_id = match use_token(env, "reset", token).await? {
+        Some(user_id) => user_id,
+        None => return Ok(Ok(false)),
+    };
+
+    match get_user(env, &user_id).await? {
+        Some(mut user) => {
+            user.account.hash = crypto_helpers::hash_password(password);
+            put_account(env, &user.user_id, &user.account).await?;
+            revoke_sessions(env, &user.user_id, None).await?;
+            Ok(Ok(true))
+        }
+        None => Ok(Ok(false)),
+    }
+}
+
+/*
+ * Change the password of a logged in user, their other sessions are logged out
+ * */
+pub async fn change_password<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    session_id: &str,
+    current: &str,
+    password: &str,
+) -> Result<Option<AccountError>> {
+    if !crypto_helpers::verify_password(current, &user.account.hash) {
+        return Ok(Some(AccountError::WrongPassword));
+    }
+    if let Some(error) = validate_password(password) {
+        return Ok(Some(error));
+    }
+
+    let mut account = user.account.clone();
+    account.hash = crypto_helpers::hash_password(password);
+    put_account(env, &user.user_id, &account).await?;
+    revoke_sessions(env, &user.user_id, Some(session_id)).await?;
+    Ok(None)
+}
+
+pub async fn change_username<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    username: &str,
+) -> Result<Option<AccountError>> {
+    if let Some(error) = validate_username(username) {
+        return Ok(Some(error));
+    }
+    if !claim_username(env, username, &user.user_id, None).await? {
+        return Ok(Some(AccountError::UsernameTaken));
+    }
+    // Names that look the same share an index key. Legacy accounts can share a name, in which case
+    // the key may belong to another of them
+    let old_key = get_username_key(&user.account.username);
+    if get_username_key(username) != old_key {
+        let users_kv = env.store("USERS")?;
+        if users_kv.get(old_key.as_str()).await?.as_deref() == Some(user.user_id.as_str()) {
+            users_kv.delete(old_key.as_str()).await?;
+        }
+    }
+
+    let mut account = user.account.clone();
+    account.username = username.to_string();
+    put_account(env, &user.user_id, &account).await?;
+    Ok(None)
+}
+
+pub async fn get_user_by_username<E: Backend>(
+    env: &E,
+    username: &str,
+) -> Result<Option<user_obj::User>> {
+    let users_kv = env.store("USERS")?;
+    match users_kv.get(get_username_key(username).as_str()).await? {
+        Some(user_id) => get_user(env, &user_id).await,
+        None => Ok(None),
+    }
+}
+
+pub async fn set_role<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    role: user_obj::Role,
+) -> Result<()> {
+    let mut account = user.account.clone();
+    account.role = role;
+    put_account(env, &user.user_id, &account).await
+}
+
+/*
+ * Ban or suspend a user, or lift it with None. Banning logs them out everywhere
+ * */
+pub async fn set_ban<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    ban: Option<user_obj::Ban>,
+) -> Result<()> {
+    let banned = ban.is_some();
+    let mut account = user.account.clone();
+    account.ban = ban;
+    put_account(env, &user.user_id, &account).await?;
+    if banned {
+        revoke_sessions(env, &user.user_id, None).await?;
+    }
+    Ok(())
+}
+
+/*
+ * Reserve a username for a user, false if someone else already has one that looks the same.
+ * Accounts from before the index existed claim theirs when they next log in
+ * */
+async fn claim_username<E: Backend>(
+    env: &E,
+    username: &str,
+    user_id: &str,
+    ttl: Option<u64>,
+) -> Result<bool> {
+    let users_kv = env.store("USERS")?;
+    let key = get_username_key(username);
+    match users_kv.get(key.as_str()).await? {
+        Some(owner) if owner != user_id => Ok(false),
+        _ => {
+            match ttl {
+                Some(ttl) => users_kv.put_with_ttl(key.as_str(), user_id, ttl).await?,
+                None => users_kv.put(key.as_str(), user_id).await?,
+            }
+            Ok(true)
+        }
+    }
+}
+
+fn get_username_key(username: &str) -> String {
+    format!("username:{}", username_skeleton(username))
+}
+
+async fn put_account<E: Backend>(
+    env: &E,
+    user_id: &str,
+    account: &user_obj::UserAccount,
+) -> Result<()> {
+    put_account_with_ttl(env, user_id, account, None).await
+}
+
+/*
+ * Write an account along with its email index entry, both expire with ttl
+ * */
+async fn put_account_with_ttl<E: Backend>(
+    env: &E,
+    user_id: &str,
+    account: &user_obj::UserAccount,
+    ttl: Option<u64>,
+) -> Result<()> {
+    let users_kv = env.store("USERS")?;
+    let serialized = serde_json::to_string(account)?;
+    let email_key = get_email_key(&account.email);
+    match ttl {
+        Some(ttl) => {
+            users_kv.put_with_ttl(user_id, &serialized, ttl).await?;
+            users_kv.put_with_ttl(&email_key, user_id, ttl).await?;
+        }
+        None => {
+            users_kv.put(user_id, &serialized).await?;
+            // Legacy accounts are still keyed by their email and have no index entry
+            if !account.email.is_empty() {
+                users_kv.put(&email_key, user_id).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Emails are matched case insensitively
+ * */
+fn get_email_key(email: &str) -> String {
+    format!("email:{}", email.trim().to_lowercase())
+}
+
+/*
+ * Move a page of accounts from before user ids to a generated id, returning how many were moved
+ * and the cursor for the next page. Existing sessions and verification or reset tokens keep
+ * working as get_user resolves their email through the index
+ * */
+pub async fn migrate_legacy_users<E: Backend>(
+    env: &E,
+    cursor: Option<&str>,
+    limit: u64,
+) -> Result<(usize, Option<String>)> {
+    let users_kv = env.store("USERS")?;
+    let page = users_kv.list_page("", cursor, limit).await?;
+    let mut migrated = 0;
+    for key in page.keys {
+        let email = key.name;
+        if !is_legacy_id(&email) {
+            continue;
+        }
+        let mut user = match get_account(env, &email).await? {
+            Some(user) => user,
+            None => continue,
+        };
+        let user_id = Uuid::new_v4().to_simple().to_string();
+        user.account.email = email.to_string();
+        // Pending accounts still expire if they are never verified
+        let ttl = match user.account.status {
+            user_obj::AccountStatus::Pending => Some(VERIFICATION_EXPIRY),
+            user_obj::AccountStatus::Active => None,
+        };
+        put_account_with_ttl(env, &user_id, &user.account, ttl).await?;
+
+        let username_key = get_username_key(&user.account.username);
+        match users_kv.get(username_key.as_str()).await? {
+            Some(owner) if owner != email => {}
+            _ => users_kv.put(username_key.as_str(), &user_id).await?,
+        }
+        users_kv.delete(&email).await?;
+        migrated += 1;
+    }
+    Ok((migrated, page.cursor))
+}
+
+/*
+ * Signed single use token for a user, stored until it expires under {kind}:{id}.
+ * Tokens share the USERS namespace, the prefixes can't appear in an email address
+ * */
+async fn create_token<E: Backend>(env: &E, kind: &str, user_id: &str, ttl: u64) -> Result<String> {
+    let token_id = Uuid::new_v4().to_simple().to_string();
+    env.store("USERS")?
+        .put_with_ttl(format!("{}:{}", kind, token_id).as_str(), user_id, ttl)
+        .await?;
+    Ok(crypto_helpers::sign_token(
+        &env.var("TOKEN_SECRET")?,
+        &token_id,
+    ))
+}
+
+/*
+ * The user a token was created for, removing the token so that it can't be used again
+ * */
+async fn use_token<E: Backend>(env: &E, kind: &str, token: &str) -> Result<Option<String>> {
+    let secret = env.var("TOKEN_SECRET")?;
+    let token_id = match crypto_helpers::verify_token(&secret, token) {
+        Some(token_id) => token_id,
+        None => return Ok(None),
     };
-    let serialized = serde_json::to_string(&acc).unwrap();
 
-    if get_user(env, user_id).await?.is_some() {
-        return Ok(None);
+    let users_kv = env.store("USERS")?;
+    let key = format!("{}:{}", kind, token_id);
+    let user_id = users_kv.get(key.as_str()).await?;
+    if user_id.is_some() {
+        users_kv.delete(key.as_str()).await?;
+    }
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use futures::executor::block_on;
+
+    /*
+     * Create and verify an account, returning a new session for it
+     * */
+    async fn create_verified_user(env: &MemoryBackend, email: &str) -> String {
+        let token = create_user(env, email, "a", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        verify_user(env, &token).await.unwrap().unwrap();
+        create_session(env, email, "password", &Default::default())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn skeletons_match_lookalikes() {
+        let skeleton = username_skeleton("alice");
+        for lookalike in &["Alice", "ALICE", "аlice", "al1ce", "alíce", "ａｌｉｃｅ"] {
+            assert_eq!(username_skeleton(lookalike), skeleton, "{}", lookalike);
+        }
+        assert_eq!(username_skeleton("modern"), username_skeleton("modem"));
+        assert_eq!(username_skeleton("a-b"), username_skeleton("a_b"));
+        assert_ne!(username_skeleton("alice"), username_skeleton("alicia"));
+    }
+
+    #[test]
+    fn reserved_usernames() {
+        for username in &["admin", "Adm1n", "DELETED", "mоderator"] {
+            assert_eq!(
+                validate_username(username),
+                Some(AccountError::ReservedUsername),
+                "{}",
+                username
+            );
+        }
+        assert_eq!(validate_username("admiral"), None);
+        assert_eq!(validate_username("émile"), None);
+        assert_eq!(
+            validate_username("<b>"),
+            Some(AccountError::InvalidUsername)
+        );
+    }
+
+    #[test]
+    fn legacy_users_claim_username_on_login() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            put_legacy_account(&env, "a@example.com", "alice").await;
+            create_session(&env, "a@example.com", "password", &Default::default())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                create_user(&env, "b@example.com", "Alicе", "password")
+                    .await
+                    .unwrap(),
+                Err(AccountError::UsernameTaken)
+            );
+        });
     }
 
-    let users_kv = env.kv("USERS")?;
-    users_kv.put(user_id, serialized)?.execute().await?;
+    /*
+     * An account as stored before user ids, keyed by its email
+     * */
+    async fn put_legacy_account(env: &MemoryBackend, email: &str, username: &str) {
+        let account = user_obj::UserAccount {
+            email: String::new(),
+            hash: crypto_helpers::hash_password("password"),
+            username: username.to_string(),
+            status: user_obj::AccountStatus::Active,
+            role: user_obj::Role::User,
+            ban: None,
+        };
+        put_account(env, email, &account).await.unwrap();
+    }
+
+    #[test]
+    fn legacy_users_are_migrated_to_ids() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            put_legacy_account(&env, "a@example.com", "alice").await;
+            let session_id = create_session(&env, "a@example.com", "password", &Default::default())
+                .await
+                .unwrap()
+                .unwrap();
+            create_verified_user(&env, "b@example.com").await;
+
+            let (migrated, cursor) = migrate_legacy_users(&env, None, 2).await.unwrap();
+            assert_eq!(migrated, 1);
+            assert!(cursor.is_some());
+            let (migrated, _) = migrate_legacy_users(&env, cursor.as_deref(), 100)
+                .await
+                .unwrap();
+            assert_eq!(migrated, 0);
+
+            let user = get_user_by_email(&env, "A@example.com")
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(!is_legacy_id(&user.user_id));
+            assert_eq!(user.account.email, "a@example.com");
+            assert_eq!(user.account.username, "alice");
+            // References that still use the email resolve to the migrated account
+            let by_email = get_user(&env, "a@example.com").await.unwrap().unwrap();
+            assert_eq!(by_email.user_id, user.user_id);
+            let session_user = get_session(&env, &session_id).await.unwrap().unwrap();
+            assert_eq!(session_user.user_id, user.user_id);
+            assert!(
+                create_session(&env, "a@example.com", "password", &Default::default())
+                    .await
+                    .unwrap()
+                    .is_ok()
+            );
+            assert!(change_username(&env, &user, "alicia")
+                .await
+                .unwrap()
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn create_user_is_pending_until_verified() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let token = create_user(&env, "a@example.com", "a", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            let user = get_user_by_email(&env, "a@example.com")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(user.account.status, user_obj::AccountStatus::Pending);
+            assert!(!is_legacy_id(&user.user_id));
+            assert_eq!(
+                create_session(&env, "a@example.com", "password", &Default::default())
+                    .await
+                    .unwrap(),
+                Err(LoginError::Unverified)
+            );
+
+            assert_eq!(
+                verify_user(&env, &token).await.unwrap(),
+                Some(user.user_id.to_string())
+            );
+            let session_id = create_session(&env, "a@example.com", "password", &Default::default())
+                .await
+                .unwrap()
+                .unwrap();
+            let session_user = get_session(&env, &session_id).await.unwrap().unwrap();
+            assert_eq!(session_user.user_id, user.user_id);
+            assert_eq!(session_user.account.username, "a");
+            assert_eq!(session_user.account.status, user_obj::AccountStatus::Active);
+        });
+    }
+
+    #[test]
+    fn verification_tokens_work_once() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let token = create_user(&env, "a@example.com", "a", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            let (token_id, _) = token.split_once('.').unwrap();
+            let forged = format!("{}.{}", token_id, "00".repeat(32));
+            assert_eq!(verify_user(&env, &forged).await.unwrap(), None);
+            assert_eq!(verify_user(&env, "nonsense").await.unwrap(), None);
+
+            assert!(verify_user(&env, &token).await.unwrap().is_some());
+            assert_eq!(verify_user(&env, &token).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn create_user_rejects_existing_email() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let first = create_user(&env, "a@example.com", "a", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            // Until it is verified registering again starts over, freeing the old username
+            let second = create_user(&env, " A@Example.com", "b", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(verify_user(&env, &first).await.unwrap(), None);
+            assert!(create_user(&env, "c@example.com", "a", "password")
+                .await
+                .unwrap()
+                .is_ok());
+
+            assert!(verify_user(&env, &second).await.unwrap().is_some());
+            let third = create_user(&env, "a@example.com", "d", "password")
+                .await
+                .unwrap();
+            assert_eq!(third, Err(AccountError::EmailTaken));
+        });
+    }
 
-    let session_id = create_session(env, user_id, password)
-        .await?
-        .expect("Create session failed when it shouldn't have");
-    Ok(Some(session_id))
+    #[test]
+    fn create_user_checks_the_email() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            for email in &[
+                "a",
+                "a@example",
+                "@example.com",
+                "a b@example.com",
+                "a@.com",
+            ] {
+                assert_eq!(
+                    create_user(&env, *email, "a", "password").await.unwrap(),
+                    Err(AccountError::InvalidEmail)
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn create_user_validates_and_claims_username() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            create_user(&env, "a@example.com", "alice", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            let create =
+                |username, password| create_user(&env, "b@example.com", username, password);
+            assert_eq!(
+                create("ALICE", "password").await.unwrap(),
+                Err(AccountError::UsernameTaken)
+            );
+            assert_eq!(
+                create("bob smith", "password").await.unwrap(),
+                Err(AccountError::InvalidUsername)
+            );
+            assert_eq!(
+                create("", "password").await.unwrap(),
+                Err(AccountError::InvalidUsername)
+            );
+            assert_eq!(
+                create("bob", "short").await.unwrap(),
+                Err(AccountError::PasswordTooShort)
+            );
+            assert!(create("bob", "password").await.unwrap().is_ok());
+        });
+    }
+
+    #[test]
+    fn change_username_releases_old_name() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let session_id = create_verified_user(&env, "a@example.com").await;
+            let token = create_user(&env, "b@example.com", "b", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            verify_user(&env, &token).await.unwrap();
+            let user = get_session(&env, &session_id).await.unwrap().unwrap();
+
+            assert_eq!(
+                change_username(&env, &user, "B").await.unwrap(),
+                Some(AccountError::UsernameTaken)
+            );
+            assert_eq!(
+                change_username(&env, &user, "a!").await.unwrap(),
+                Some(AccountError::InvalidUsername)
+            );
+            assert_eq!(change_username(&env, &user, "A").await.unwrap(), None);
+            assert_eq!(change_username(&env, &user, "c").await.unwrap(), None);
+            let user = get_session(&env, &session_id).await.unwrap().unwrap();
+            assert_eq!(user.account.username, "c");
+
+            // The old name is free for someone else
+            let token = create_user(&env, "d@example.com", "a", "password")
+                .await
+                .unwrap();
+            assert!(token.is_ok());
+        });
+    }
+
+    #[test]
+    fn change_username_keeps_a_shared_legacy_name() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            put_legacy_account(&env, "a@example.com", "alice").await;
+            put_legacy_account(&env, "b@example.com", "alice").await;
+            let mut sessions = Vec::new();
+            for email in &["a@example.com", "b@example.com"] {
+                let session_id = create_session(&env, *email, "password", &Default::default())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                sessions.push(session_id);
+            }
+            let session_id = &sessions[1];
+            let user = get_session(&env, &session_id).await.unwrap().unwrap();
+
+            assert_eq!(change_username(&env, &user, "bob").await.unwrap(), None);
+            let owner = get_user_by_username(&env, "alice").await.unwrap().unwrap();
+            assert_eq!(owner.user_id, "a@example.com");
+        });
+    }
+
+    #[test]
+    fn change_password_needs_current_password() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let session_id = create_verified_user(&env, "a@example.com").await;
+            let other_session_id =
+                create_session(&env, "a@example.com", "password", &Default::default())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let user = get_session(&env, &session_id).await.unwrap().unwrap();
+
+            let change =
+                |current, password| change_password(&env, &user, &session_id, current, password);
+            assert_eq!(
+                change("wrong", "new password").await.unwrap(),
+                Some(AccountError::WrongPassword)
+            );
+            assert_eq!(
+                change("password", "short").await.unwrap(),
+                Some(AccountError::PasswordTooShort)
+            );
+            assert_eq!(change("password", "new password").await.unwrap(), None);
+
+            // Only the session that changed the password stays logged in
+            assert!(get_session(&env, &session_id).await.unwrap().is_some());
+            assert!(get_session(&env, &other_session_id)
+                .await
+                .unwrap()
+                .is_none());
+            assert!(
+                create_session(&env, "a@example.com", "new password", &Default::default())
+                    .await
+                    .unwrap()
+                    .is_ok()
+            );
+        });
+    }
+
+    #[test]
+    fn create_session_checks_password() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            create_verified_user(&env, "a@example.com").await;
+            assert_eq!(
+                create_session(&env, "a@example.com", "wrong", &Default::default())
+                    .await
+                    .unwrap(),
+                Err(LoginError::InvalidCredentials)
+            );
+            assert_eq!(
+                create_session(&env, "b@example.com", "password", &Default::default())
+                    .await
+                    .unwrap(),
+                Err(LoginError::InvalidCredentials)
+            );
+            assert!(
+                create_session(&env, "a@example.com", "password", &Default::default())
+                    .await
+                    .unwrap()
+                    .is_ok()
+            );
+        });
+    }
+
+    #[test]
+    fn failed_logins_lock_out() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            create_verified_user(&env, "a@example.com").await;
+            let login = |email: String, password: &'static str, ip: &'static str| {
+                let env = &env;
+                async move {
+                    let client = user_obj::Client {
+                        ip: Some(ip.to_string()),
+                        ..Default::default()
+                    };
+                    create_session(env, email.as_str(), password, &client)
+                        .await
+                        .unwrap()
+                }
+            };
+            let a = || "a@example.com".to_string();
+
+            for _ in 0..throttle::ACCOUNT_BACKOFF.max_failures {
+                assert_eq!(
+                    login(a(), "wrong", "1").await,
+                    Err(LoginError::InvalidCredentials)
+                );
+            }
+            assert_eq!(
+                login(a(), "wrong", "1").await,
+                Err(LoginError::TooManyAttempts(30))
+            );
+            // Even the right password is refused until the lockout ends, from anywhere
+            assert_eq!(
+                login("A@example.com".to_string(), "password", "2").await,
+                Err(LoginError::TooManyAttempts(30))
+            );
+            env.advance(30_000);
+            assert!(login(a(), "password", "2").await.is_ok());
+            assert_eq!(
+                login(a(), "wrong", "2").await,
+                Err(LoginError::InvalidCredentials)
+            );
+
+            // Trying many emails from one address locks the address out
+            for attempt in 0..throttle::IP_BACKOFF.max_failures {
+                let email = format!("{}@example.com", attempt);
+                login(email, "wrong", "3").await.unwrap_err();
+            }
+            assert!(matches!(
+                login(a(), "password", "3").await,
+                Err(LoginError::TooManyAttempts(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn admin_emails_are_made_admins() {
+        let mut env = MemoryBackend::new();
+        env.set_var("ADMIN_EMAILS", "x@example.com, A@example.com");
+        block_on(async {
+            let session_id = create_verified_user(&env, "a@example.com").await;
+            let user = get_session(&env, &session_id).await.unwrap().unwrap();
+            assert_eq!(user.account.role, user_obj::Role::Admin);
+
+            let other = create_user(&env, "b@example.com", "b", "password")
+                .await
+                .unwrap()
+                .unwrap();
+            verify_user(&env, &other).await.unwrap();
+            let other = get_user_by_username(&env, "B").await.unwrap().unwrap();
+            assert_eq!(other.account.role, user_obj::Role::User);
+            set_role(&env, &other, user_obj::Role::Moderator)
+                .await
+                .unwrap();
+            let other = get_user_by_email(&env, "b@example.com")
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(other.can_moderate());
+        });
+    }
+
+    #[test]
+    fn delete_session_logs_out() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let session_id = create_verified_user(&env, "a@example.com").await;
+            delete_session(&env, &session_id).await.unwrap();
+            assert!(get_session(&env, &session_id).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn sessions_are_listed_and_revoked() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let session_id = create_verified_user(&env, "a@example.com").await;
+            let client = user_obj::Client {
+                user_agent: Some("phone".to_string()),
+                region: Some("Scotland".to_string()),
+                ip: None,
+            };
+            env.advance(60_000);
+            let other_session_id = create_session(&env, "a@example.com", "password", &client)
+                .await
+                .unwrap()
+                .unwrap();
+            let user = get_session(&env, &session_id).await.unwrap().unwrap();
+            let created_at = env.now() - 60_000;
+            env.advance(60_000);
+            get_session(&env, &session_id).await.unwrap();
+
+            let sessions = list_sessions(&env, &user.user_id).await.unwrap();
+            assert_eq!(sessions.len(), 2);
+            assert_eq!(sessions[0].session_id, session_id);
+            assert_eq!(sessions[0].info.created_at, Some(created_at));
+            assert_eq!(sessions[0].info.last_seen, env.now());
+            assert_eq!(sessions[1].info.client, client);
+
+            assert!(!revoke_session(&env, &user.user_id, &other_session_id)
+                .await
+                .unwrap());
+            let public_id = crypto_helpers::public_id(&other_session_id);
+            assert!(!revoke_session(&env, "someone else", &public_id)
+                .await
+                .unwrap());
+            assert!(revoke_session(&env, &user.user_id, &public_id)
+                .await
+                .unwrap());
+            assert!(get_session(&env, &other_session_id)
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(list_sessions(&env, &user.user_id).await.unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn reset_password_revokes_sessions() {
+        let env = MemoryBackend::new();
+        block_on(async {
+            let session_id = create_verified_user(&env, "a@example.com").await;
+            let other_session_id =
+                create_session(&env, "a@example.com", "password", &Default::default())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert!(create_password_reset(&env, "b@example.com")
+                .await
+                .unwrap()
+                .is_none());
+
+            let token = create_password_reset(&env, "a@example.com")
+                .await
+                .unwrap()
+                .unwrap();
+            // Tokens only work for what they were created for
+            assert_eq!(verify_user(&env, &token).await.unwrap(), None);
+            assert_eq!(
+                reset_password(&env, &token, "short").await.unwrap(),
+                Err(AccountError::PasswordTooShort)
+            );
+            assert_eq!(
+                reset_password(&env, &token, "new password").await.unwrap(),
+                Ok(true)
+            );
+            assert_eq!(
+                reset_password(&env, &token, "new password").await.unwrap(),
+                Ok(false)
+            );
+
+            assert!(get_session(&env, &session_id).await.unwrap().is_none());
+            assert!(get_session(&env, &other_session_id)
+                .await
+                .unwrap()
+                .is_none());
+            assert!(
+                create_session(&env, "a@example.com", "password", &Default::default())
+                    .await
+                    .unwrap()
+                    .is_err()
+            );
+            assert!(
+                create_session(&env, "a@example.com", "new password", &Default::default())
+                    .await
+                    .unwrap()
+                    .is_ok()
+            );
+        });
+    }
 }
@@ -0,0 +1,136 @@
+use crate::cookie;
+use crate::csrf::{self, CSRF_FIELD};
+use crate::db::memory::MemoryBackend;
+use crate::db::store::Backend;
+use crate::db::user::{create_session, create_user, verify_user};
+use crate::handle_request;
+use crate::http::{HttpRequest, HttpResponse};
+use futures::executor::block_on;
+use worker::Method;
+
+/*
+ * Builds a request and runs it through the same routing as the fetch handler
+ * */
+pub struct TestRequest {
+    request: HttpRequest,
+    cookies: Vec<String>,
+}
+
+impl TestRequest {
+    pub fn get(path: &str) -> Self {
+        TestRequest {
+            request: HttpRequest::new(Method::Get, path),
+            cookies: Vec::new(),
+        }
+    }
+
+    pub fn post(path: &str) -> Self {
+        TestRequest {
+            request: HttpRequest::new(Method::Post, path),
+            cookies: Vec::new(),
+        }
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.request.method = method;
+        self
+    }
+
+    /*
+     * Query params without a value, e.g. ?login
+     * */
+    pub fn query(self, name: &str) -> Self {
+        self.query_value(name, "")
+    }
+
+    pub fn query_value(mut self, name: &str, value: &str) -> Self {
+        self.request
+            .query
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.request
+            .headers
+            .insert(name.to_lowercase(), value.to_string());
+        self
+    }
+
+    pub fn body(mut self, body: &str) -> Self {
+        self.request.body = body.to_string();
+        self
+    }
+
+    pub fn cookie(mut self, name: &str, value: &str) -> Self {
+        self.cookies.push(format!("{}={}", name, value));
+        self
+    }
+
+    pub fn form(mut self, name: &str, value: &str) -> Self {
+        self.request
+            .form
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /*
+     * Posts with a session carry its csrf token, as they would from a rendered form, unless the
+     * test sets one itself
+     * */
+    pub fn send<E: Backend>(self, env: &E) -> HttpResponse {
+        let mut request = self.request;
+        let session_id = self.cookies.iter().find_map(|cookie| {
+            cookie
+                .strip_prefix(cookie::SESSION_COOKIE)
+                .and_then(|cookie| cookie.strip_prefix('='))
+        });
+        if let (Method::Post, Some(session_id)) = (&request.method, session_id) {
+            if !request.form.contains_key(CSRF_FIELD) {
+                let token = csrf::token(env, session_id).unwrap();
+                request.form.insert(CSRF_FIELD.to_string(), token);
+            }
+        }
+        if !self.cookies.is_empty() {
+            request
+                .headers
+                .insert("cookie".to_string(), self.cookies.join("; "));
+        }
+        block_on(handle_request(request, env)).expect("Request returned an error")
+    }
+}
+
+/*
+ * Register a user and return their session id
+ * */
+pub fn register<E: Backend>(env: &E, email: &str, username: &str) -> String {
+    block_on(async {
+        let token = create_user(env, email, username, "password")
+            .await
+            .unwrap()
+            .expect("User could not be created");
+        verify_user(env, &token).await.unwrap();
+        create_session(env, email, "password", &Default::default())
+            .await
+            .unwrap()
+            .unwrap()
+    })
+}
+
+/*
+ * The token from the link in the most recent mail
+ * */
+pub fn emailed_token(env: &MemoryBackend) -> String {
+    let mail = env.outbox().pop().expect("No mail was sent");
+    let (_, token) = mail.body.split_once("token=").expect("Mail has no link");
+    token.split_whitespace().next().unwrap().to_string()
+}
+
+/*
+ * The session id a response sets, empty if it clears the session cookie
+ * */
+pub fn session_from_cookie(response: &HttpResponse) -> Option<String> {
+    response
+        .header("Set-Cookie")
+        .and_then(|header| cookie::parse(header).remove(cookie::SESSION_COOKIE))
+}
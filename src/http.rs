@@ -0,0 +1,168 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use worker::*;
+
+use crate::cookie;
+use crate::user_obj;
+
+/*
+ * Longest user agent kept for a session, anything past it is cut off
+ * */
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/*
+ * Plain rust representation of an incoming request so that routing can run outside of a worker
+ * */
+pub struct HttpRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub form: HashMap<String, String>,
+    pub body: String,
+    /*
+     * Cloudflare region the request came from, if known
+     * */
+    pub region: Option<String>,
+}
+
+impl HttpRequest {
+    pub fn new<S: AsRef<str>>(method: Method, path: S) -> Self {
+        HttpRequest {
+            method,
+            path: path.as_ref().to_string(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            form: HashMap::new(),
+            body: String::new(),
+            region: None,
+        }
+    }
+
+    pub async fn from_worker(mut req: Request) -> Result<Self> {
+        let mut request = HttpRequest::new(req.method(), req.path());
+        request.region = req.cf().region();
+        request.query = req.url()?.query_pairs().into_owned().collect();
+        request.headers = req
+            .headers()
+            .entries()
+            .map(|(name, value)| (name.to_lowercase(), value))
+            .collect();
+
+        // Forms are submitted url encoded, so the body can be parsed the same way as the query
+        if request.method == Method::Post {
+            request.body = req.text().await?;
+            request.form = url::form_urlencoded::parse(request.body.as_bytes())
+                .into_owned()
+                .collect();
+        }
+        Ok(request)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_lowercase())
+            .map(|value| value.as_str())
+    }
+
+    /*
+     * The device making the request, as recorded against new sessions
+     * */
+    pub fn client(&self) -> user_obj::Client {
+        user_obj::Client {
+            user_agent: self
+                .header("User-Agent")
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+            region: self.region.clone(),
+            ip: self.header("CF-Connecting-IP").map(str::to_string),
+        }
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.header("Cookie")
+            .and_then(|cookies| cookie::parse(cookies).remove(name))
+    }
+}
+
+/*
+ * Plain rust representation of a response, converted to a worker Response before being returned
+ * */
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn empty() -> Result<Self> {
+        Ok(HttpResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: String::new(),
+        })
+    }
+
+    pub fn from_html<S: AsRef<str>>(html: S) -> Result<Self> {
+        Ok(HttpResponse {
+            status: 200,
+            headers: vec![("content-type".to_string(), "text/html".to_string())],
+            body: html.as_ref().to_string(),
+        })
+    }
+
+    pub fn from_json<B: Serialize>(value: &B) -> Result<Self> {
+        Ok(HttpResponse {
+            status: 200,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: serde_json::to_string(value)?,
+        })
+    }
+
+    pub fn error<S: Into<String>>(msg: S, status: u16) -> Result<Self> {
+        Ok(HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: msg.into(),
+        })
+    }
+
+    /*
+     * See other, so that the browser follows up a form submission with a GET
+     * */
+    pub fn redirect<S: AsRef<str>>(location: S) -> Result<Self> {
+        Ok(HttpResponse::empty()?
+            .with_status(303)
+            .with_header("Location", location))
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_header<S: AsRef<str>, S2: AsRef<str>>(mut self, name: S, value: S2) -> Self {
+        self.headers
+            .push((name.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn into_worker(self) -> Result<Response> {
+        let mut headers = Headers::new();
+        for (name, value) in &self.headers {
+            headers.append(name, value)?;
+        }
+        let response = if self.body.is_empty() {
+            Response::empty()?
+        } else {
+            Response::ok(self.body)?
+        };
+        Ok(response.with_status(self.status).with_headers(headers))
+    }
+}
@@ -1,45 +1,62 @@
+use crate::db::store::Backend;
 use crate::db::user::*;
-use std::collections::HashMap;
 
 use worker::*;
+mod api;
+mod audit_obj;
+mod content_filter;
+mod cookie;
 mod crypto_helpers;
+mod csrf;
 mod db;
+#[cfg(test)]
+mod harness;
+mod http;
+mod mail;
 mod post;
 mod post_obj;
 mod render;
+mod report_obj;
 mod user_obj;
 mod utils;
+use http::{HttpRequest, HttpResponse};
 use post::handle_post_request;
-use render::render_page;
+use render::{
+    render_account, render_audit, render_banned, render_forgot_password, render_history,
+    render_page, render_reports, render_reset_password, render_verification, ViewOptions,
+};
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env) -> Result<Response> {
     utils::log_request(&req);
     utils::set_panic_hook();
 
-    // Get session_id
-    let mut session_id = req
-        .headers()
-        .get("Cookie")?
-        .map(|cookies| {
-            let map: HashMap<_, _> = cookies
-                .split(';')
-                .map(|cookie| {
-                    let kvp = cookie
-                        .split('=')
-                        .take(2)
-                        .map(|text| text.trim())
-                        .collect::<Vec<&str>>();
-                    (kvp[0].to_owned(), kvp[1].to_owned())
-                })
-                .collect();
-            map.get("sessionId").map(|session_id| session_id.to_owned())
-        })
-        .flatten();
+    let result = match HttpRequest::from_worker(req).await {
+        Ok(req) => handle_request(req, &env).await,
+        Err(error) => Err(error),
+    };
+
+    // If the route returns an error, replace it with an error response and return it to the user.
+    match result {
+        Ok(response) => response.into_worker(),
+        Err(error) => {
+            console_log!("An error occured: {}", error);
+            Response::error("An error has occured", 500)
+        }
+    }
+}
+
+async fn handle_request<E: Backend>(req: HttpRequest, env: &E) -> Result<HttpResponse> {
+    // Get session_id, api clients may send it as a bearer token instead of a cookie
+    let mut session_id = req.cookie(cookie::SESSION_COOKIE).or_else(|| {
+        req.header("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map(|session_id| session_id.trim().to_string())
+    });
 
     // Get user for session if valid session else None
     let user = if let Some(ref session_id) = session_id {
-        get_session(&env, &session_id).await?
+        get_session(env, session_id).await?
     } else {
         None
     };
@@ -47,18 +64,67 @@ pub async fn main(req: Request, env: Env) -> Result<Response> {
     // remove session_ids that do not correspond to a valid session
     session_id = session_id.filter(|_| user.is_some());
 
-    let result = match req.method() {
-        Method::Get => render_page(&req.path(), &env, false, user).await,
-        Method::Post => handle_post_request(req, &env, user, session_id).await,
-        _ => Response::error("Only GET and POST methods are allowed", 405),
-    };
-
-    // If the route returns an error, replace it with an error response and return it to the user.
-    match result {
-        Ok(response) => Ok(response),
-        Err(error) => {
-            console_log!("An error occured: {}", error);
-            Response::error("An error has occured", 500)
+    // Banning revokes sessions, this catches any started while the ban was being made
+    if let (Some(user), Some(session_id)) = (&user, &session_id) {
+        if let Some(ban) = user.active_ban(env.now()) {
+            delete_session(env, session_id).await?;
+            return match req.path.starts_with("/api/") {
+                true => api::api_error(LoginError::Banned(ban.clone()).message(), 403),
+                false => {
+                    Ok(render_banned(ban)?
+                        .with_header("Set-Cookie", cookie::clear_session_cookie()))
+                }
+            };
         }
     }
+
+    // Other sites can't submit anything, whatever the route
+    if req.method != Method::Get && !csrf::same_origin(&req) {
+        return HttpResponse::error("Error, cross-site request refused", 403);
+    }
+
+    if req.path.starts_with("/api/") {
+        return api::handle_api_request(req, env, user, session_id).await;
+    }
+
+    let csrf_token = match &session_id {
+        Some(session_id) => Some(csrf::token(env, session_id)?),
+        None => None,
+    };
+    let response = match req.method {
+        Method::Get if req.query.contains_key("history") => render_history(&req.path, env).await,
+        Method::Get if req.path == "/verify" && req.query.contains_key("token") => {
+            render_verification(env, &req.query["token"]).await
+        }
+        Method::Get if req.query.contains_key("forgot") => render_forgot_password(),
+        Method::Get if req.query.contains_key("account") => match (&user, &session_id) {
+            (Some(user), Some(session_id)) => render_account(env, user, session_id, None).await,
+            _ => HttpResponse::error("Error, User is not logged in!", 401),
+        },
+        Method::Get if req.query.contains_key("audit") => match &user {
+            Some(user) if user.account.role == user_obj::Role::Admin => {
+                render_audit(env, &req.query).await
+            }
+            Some(_) => HttpResponse::error("Error: Insufficient permissions", 403),
+            None => HttpResponse::error("Error, User is not logged in!", 401),
+        },
+        Method::Get if req.query.contains_key("reports") => match &user {
+            Some(user) if user.can_moderate() => render_reports(env).await,
+            Some(_) => HttpResponse::error("Error: Insufficient permissions", 403),
+            None => HttpResponse::error("Error, User is not logged in!", 401),
+        },
+        Method::Get if req.path == "/reset" && req.query.contains_key("token") => {
+            render_reset_password(&req.query["token"])
+        }
+        Method::Get => {
+            let options = ViewOptions::from_query(&req.query);
+            render_page(&req.path, env, None, user, &options).await
+        }
+        Method::Post => handle_post_request(req, env, user, session_id).await,
+        _ => HttpResponse::error("Only GET and POST methods are allowed", 405),
+    }?;
+    Ok(match csrf_token {
+        Some(token) => csrf::add_to_forms(response, &token),
+        None => response,
+    })
 }
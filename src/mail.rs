@@ -0,0 +1,148 @@
+use crate::db::store::Backend;
+use async_trait::async_trait;
+use worker::*;
+
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/*
+ * Somewhere to send mail to users, provided by the Backend
+ * */
+#[async_trait(?Send)]
+pub trait MailSender {
+    async fn send(&self, mail: &Mail) -> Result<()>;
+}
+
+/*
+ * Writes mail to the worker log instead of sending it, for development only as the links in it
+ * sign users in
+ * */
+pub struct LogMailer;
+
+#[async_trait(?Send)]
+impl MailSender for LogMailer {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        console_log!(
+            "To: {}\nSubject: {}\n\n{}",
+            mail.to,
+            mail.subject,
+            mail.body
+        );
+        Ok(())
+    }
+}
+
+/*
+ * Sends mail through the MailChannels api, authenticated by the MAILCHANNELS_API_KEY secret. Mail is
+ * sent from the MAIL_FROM var, either is None when it isn't set
+ * */
+pub struct MailChannelsMailer {
+    pub from: Option<String>,
+    pub api_key: Option<String>,
+}
+
+const MAILCHANNELS_URL: &str = "https://api.mailchannels.net/tx/v1/send";
+
+#[async_trait(?Send)]
+impl MailSender for MailChannelsMailer {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        let from = match &self.from {
+            Some(from) => from,
+            None => return Err(Error::RustError("MAIL_FROM is not set".to_string())),
+        };
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key,
+            None => {
+                return Err(Error::RustError(
+                    "MAILCHANNELS_API_KEY is not set".to_string(),
+                ))
+            }
+        };
+        let body = serde_json::json!({
+            "personalizations": [{"to": [{"email": mail.to}]}],
+            "from": {"email": from, "name": "treply"},
+            "subject": mail.subject,
+            "content": [{"type": "text/plain", "value": mail.body}],
+        });
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        headers.set("X-Api-Key", api_key)?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.to_string().into()));
+        let mut response = Fetch::Request(Request::new_with_init(MAILCHANNELS_URL, &init)?)
+            .send()
+            .await?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(Error::RustError(format!(
+                "Could not send mail, {}: {}",
+                status,
+                response.text().await.unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+/*
+ * The mailers the worker can use, MAIL_LOG="true" picks the log for development and mail is sent
+ * otherwise, so that a missing var can't leak links into the log
+ * */
+pub enum EnvMailer {
+    Log(LogMailer),
+    MailChannels(MailChannelsMailer),
+}
+
+#[async_trait(?Send)]
+impl MailSender for EnvMailer {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        match self {
+            EnvMailer::Log(mailer) => mailer.send(mail).await,
+            EnvMailer::MailChannels(mailer) => mailer.send(mail).await,
+        }
+    }
+}
+
+/*
+ * Links in mail point at SITE_URL rather than the request's host, which the client controls
+ * */
+fn site_link<E: Backend>(env: &E, path: &str) -> Result<String> {
+    Ok(format!(
+        "{}{}",
+        env.var("SITE_URL")?.trim_end_matches('/'),
+        path
+    ))
+}
+
+pub async fn send_verification<E: Backend>(env: &E, email: &str, token: &str) -> Result<()> {
+    let link = site_link(env, &format!("/verify?token={}", token))?;
+    env.mailer()
+        .send(&Mail {
+            to: email.to_string(),
+            subject: "Verify your treply account".to_string(),
+            body: format!(
+                "Follow this link to finish creating your account:\n\n{}\n\nThe link expires in a day.",
+                link
+            ),
+        })
+        .await
+}
+
+pub async fn send_password_reset<E: Backend>(env: &E, email: &str, token: &str) -> Result<()> {
+    let link = site_link(env, &format!("/reset?token={}", token))?;
+    env.mailer()
+        .send(&Mail {
+            to: email.to_string(),
+            subject: "Reset your treply password".to_string(),
+            body: format!(
+                "Follow this link to choose a new password:\n\n{}\n\nThe link expires in an hour. If you didn't ask to reset your password you can ignore this email.",
+                link
+            ),
+        })
+        .await
+}
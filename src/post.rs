@@ -1,101 +1,298 @@
-use std::collections::HashMap;
 use worker::*;
 
+use crate::audit_obj::AuditAction;
+use crate::content_filter::{self, Filtered};
+use crate::cookie::{clear_session_cookie, session_cookie};
+use crate::csrf::{self, CSRF_FIELD};
+use crate::db::audit;
 use crate::db::post::*;
+use crate::db::report::{clear_reports, report_post};
+use crate::db::store::Backend;
+use crate::db::throttle;
 use crate::db::user::*;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::mail::{send_password_reset, send_verification};
+use crate::render::{render_account, render_banned, render_message, ViewOptions};
 use crate::render_page;
+use crate::report_obj::REPORT_REASONS;
 use crate::user_obj;
 
-pub async fn handle_post_request<S: AsRef<str>>(
-    mut req: Request,
-    env: &Env,
+pub async fn handle_post_request<E: Backend, S: AsRef<str>>(
+    req: HttpRequest,
+    env: &E,
     user: Option<user_obj::User>,
     session_id: Option<S>,
-) -> Result<Response> {
+) -> Result<HttpResponse> {
     let session_id = session_id.as_ref();
     // Get post_id from path
-    let path = req.path();
+    let path = req.path.as_str();
     let post_id = path
         .strip_prefix('/')
         .expect("Expected path to begin with /");
 
     // Check if login/register param is present; if so, process login/register input
-    let url = req.url()?;
-    let pairs = url.query_pairs();
-    let hashmap: HashMap<_, _> = pairs.to_owned().collect();
+    let hashmap = &req.query;
 
     // get form data
-    let form_data = req.form_data().await?;
+    let form_data = &req.form;
 
-    // Priority is login -> register -> logout
+    // Anything done with a session has to come from a form the site rendered for it
+    if let Some(session_id) = session_id {
+        let token = form_data.get(CSRF_FIELD).map(|token| token.as_str());
+        if !csrf::verify(env, session_id.as_ref(), token)? {
+            return HttpResponse::error("Error, the form has expired, reload and try again", 403);
+        }
+    }
+
+    // Priority is login -> register -> forgot -> reset -> logout -> logoutall -> revoke -> rename
+    // -> password -> role -> ban/unban -> delete -> report -> dismiss -> restore/lock/unlock -> purge
+    // -> edit -> vote -> reply
     if hashmap.contains_key("login") {
-        if let Some(FormEntry::Field(user_id)) = form_data.get("email") {
-            if let Some(FormEntry::Field(password)) = form_data.get("password") {
-                let response = Response::empty();
-                let mut headers = Headers::new();
-
-                let session_id = create_session(env, user_id, password)
-                    .await
-                    .expect("Server failed to create session.");
-
-                if let Some(session_id) = session_id {
-                    headers
-                        .set("Set-Cookie", format!("sessionId={}", session_id).as_str())
-                        .unwrap();
-                    headers.set("Location", req.path().as_str()).unwrap();
-                    return Ok(response.unwrap().with_status(303).with_headers(headers));
-                } else {
-                    return Ok(render_page(&path, env, true, user).await?.with_status(200));
-                }
+        if let Some(email) = form_data.get("email") {
+            if let Some(password) = form_data.get("password") {
+                let session_id = create_session(env, email, password, &req.client()).await?;
+
+                return match session_id {
+                    Ok(session_id) => Ok(HttpResponse::redirect(path)?.with_header(
+                        "Set-Cookie",
+                        session_cookie(&session_id, session_expiry(env)?),
+                    )),
+                    Err(LoginError::Banned(ban)) => render_banned(&ban),
+                    Err(error) => {
+                        let response = render_page(
+                            path,
+                            env,
+                            Some(error.message()),
+                            user,
+                            &ViewOptions::default(),
+                        )
+                        .await?;
+                        Ok(match error {
+                            LoginError::TooManyAttempts(retry_after) => response
+                                .with_status(error.status())
+                                .with_header("Retry-After", retry_after.to_string()),
+                            _ => response.with_status(200),
+                        })
+                    }
+                };
             }
         }
-        return Response::error("Bad request", 400);
+        return HttpResponse::error("Bad request", 400);
     } else if hashmap.contains_key("register") {
-        if let Some(FormEntry::Field(user_id)) = form_data.get("email") {
-            if let Some(FormEntry::Field(password)) = form_data.get("password") {
-                if let Some(FormEntry::Field(username)) = form_data.get("username") {
-                    let response = Response::empty();
-                    let mut headers = Headers::new();
-
-                    let session_id = create_user(env, user_id, username, password).await?;
-
-                    if let Some(session_id) = session_id {
-                        headers
-                            .set("Set-Cookie", format!("sessionId={}", session_id).as_str())
-                            .unwrap();
-                        headers.set("Location", req.path().as_str()).unwrap();
-                        return Ok(response.unwrap().with_status(303).with_headers(headers));
-                    } else {
-                        return Ok(render_page(&path, env, true, user).await?.with_status(200));
-                    }
+        if let Some(email) = form_data.get("email") {
+            if let Some(password) = form_data.get("password") {
+                if let Some(username) = form_data.get("username") {
+                    let token = create_user(env, email, username, password).await?;
+
+                    return match token {
+                        Ok(token) => {
+                            send_verification(env, email, &token).await?;
+                            render_message(
+                                "Check your email",
+                                &format!(
+                                    "We've sent a link to {} to finish creating your account.",
+                                    html_escape::encode_text(email)
+                                ),
+                            )
+                        }
+                        Err(error) => Ok(render_page(
+                            path,
+                            env,
+                            Some(error.message()),
+                            user,
+                            &ViewOptions::default(),
+                        )
+                        .await?
+                        .with_status(200)),
+                    };
                 }
             }
         }
-        return Response::error("Bad request", 400);
+        return HttpResponse::error("Bad request", 400);
+    } else if hashmap.contains_key("forgot") {
+        return match form_data.get("email") {
+            Some(email) => {
+                if let Some(token) = create_password_reset(env, email).await? {
+                    send_password_reset(env, email, &token).await?;
+                }
+                // The same either way, so that this can't be used to find out who has an account
+                render_message(
+                    "Check your email",
+                    &format!(
+                        "If {} has an account, we've sent it a link to reset the password.",
+                        html_escape::encode_text(email)
+                    ),
+                )
+            }
+            None => HttpResponse::error("Bad request, email must be present.", 400),
+        };
+    } else if hashmap.contains_key("reset") {
+        return match (form_data.get("token"), form_data.get("password")) {
+            (Some(token), Some(password)) => match reset_password(env, token, password).await? {
+                Ok(true) => Ok(render_message(
+                    "Password reset",
+                    "Your password has been changed, you can now log in.",
+                )?
+                .with_header("Set-Cookie", clear_session_cookie())),
+                Ok(false) => Ok(render_message(
+                    "Invalid link",
+                    "This reset link is invalid or has expired, ask for a new one.",
+                )?
+                .with_status(400)),
+                Err(error) => HttpResponse::error(error.message(), error.status()),
+            },
+            _ => HttpResponse::error("Bad request, token and password must be present.", 400),
+        };
     }
 
     let user = match user {
-        None => return Response::error("Error, User is not logged in!", 401),
+        None => return HttpResponse::error("Error, User is not logged in!", 401),
         Some(user) => user,
     };
+    if let Some(ban) = user.active_ban(env.now()) {
+        return render_banned(ban);
+    }
 
     if hashmap.contains_key("logout") {
-        let mut headers = Headers::new();
-        headers
-            .set("Set-Cookie", "sessionId=deleted")
-            .expect("failed to set response header");
-        headers
-            .set("Location", req.path().as_str())
-            .expect("Failed to set response header");
-
         delete_session(
             env,
             session_id.expect("Error: User was Some but session_id was None!"),
         )
         .await?;
-        let mut headers = Headers::new();
-        headers.set("Location", req.path().as_str()).unwrap();
-        return Ok(Response::empty()?.with_status(303).with_headers(headers));
+        return Ok(HttpResponse::redirect(path)?.with_header("Set-Cookie", clear_session_cookie()));
+    }
+
+    if hashmap.contains_key("logoutall") {
+        revoke_sessions(env, &user.user_id, None).await?;
+        return Ok(HttpResponse::redirect("/")?.with_header("Set-Cookie", clear_session_cookie()));
+    }
+
+    if hashmap.contains_key("revoke") {
+        return match form_data.get("session") {
+            Some(public_id) => match revoke_session(env, &user.user_id, public_id).await? {
+                true => HttpResponse::redirect("/?account"),
+                false => HttpResponse::error("Session not found", 404),
+            },
+            None => HttpResponse::error("Bad request, session must be present.", 400),
+        };
+    }
+
+    if hashmap.contains_key("rename") {
+        let session_id = session_id.expect("Error: User was Some but session_id was None!");
+        return match form_data.get("username") {
+            Some(username) => match change_username(env, &user, username).await? {
+                None => HttpResponse::redirect("/?account"),
+                Some(error) => {
+                    Ok(
+                        render_account(env, &user, session_id.as_ref(), Some(error.message()))
+                            .await?
+                            .with_status(error.status()),
+                    )
+                }
+            },
+            None => HttpResponse::error("Bad request, username must be present.", 400),
+        };
+    }
+
+    if hashmap.contains_key("password") {
+        let session_id = session_id.expect("Error: User was Some but session_id was None!");
+        return match (form_data.get("current"), form_data.get("password")) {
+            (Some(current), Some(password)) => {
+                match change_password(env, &user, session_id.as_ref(), current, password).await? {
+                    None => HttpResponse::redirect("/?account"),
+                    Some(error) => {
+                        Ok(
+                            render_account(env, &user, session_id.as_ref(), Some(error.message()))
+                                .await?
+                                .with_status(error.status()),
+                        )
+                    }
+                }
+            }
+            _ => HttpResponse::error("Bad request, both passwords must be present.", 400),
+        };
+    }
+
+    // A reason can be given for the audit log with any action
+    let reason = form_data.get("reason").map(|reason| reason.as_str());
+
+    if hashmap.contains_key("role") {
+        if user.account.role != user_obj::Role::Admin {
+            return HttpResponse::error("Error: Insufficient permissions", 400);
+        }
+        let role = form_data
+            .get("role")
+            .and_then(|role| user_obj::Role::from_name(role));
+        return match (form_data.get("username"), role) {
+            (Some(username), Some(role)) => match get_user_by_username(env, username).await? {
+                // Admins can't demote themselves, so there is always one left
+                Some(target) if target.user_id == user.user_id => {
+                    HttpResponse::error("Error: You can't change your own role", 400)
+                }
+                Some(target) => {
+                    set_role(env, &target, role).await?;
+                    audit::record(
+                        env,
+                        &user,
+                        AuditAction::ChangeRole(role),
+                        &target.user_id,
+                        reason,
+                    )
+                    .await?;
+                    HttpResponse::redirect("/?account")
+                }
+                None => HttpResponse::error("Error: User not found", 404),
+            },
+            _ => HttpResponse::error("Bad request, username and role must be present.", 400),
+        };
+    }
+
+    if hashmap.contains_key("ban") || hashmap.contains_key("unban") {
+        if !user.can_moderate() {
+            return HttpResponse::error("Error: Insufficient permissions", 400);
+        }
+        let target = match form_data.get("username") {
+            Some(username) => match get_user_by_username(env, username).await? {
+                Some(target) => target,
+                None => return HttpResponse::error("Error: User not found", 404),
+            },
+            None => return HttpResponse::error("Bad request, username must be present.", 400),
+        };
+        // Only those with a lower role, so moderators can't ban each other, admins or themselves
+        if target.account.role >= user.account.role {
+            return HttpResponse::error("Error: Insufficient permissions", 400);
+        }
+        if hashmap.contains_key("unban") {
+            set_ban(env, &target, None).await?;
+            audit::record(env, &user, AuditAction::UnbanUser, &target.user_id, reason).await?;
+            return HttpResponse::redirect("/?account");
+        }
+        let until = match form_data.get("days").map(|days| days.as_str()) {
+            None | Some("permanent") => None,
+            Some(days) => match days.parse::<u64>() {
+                Ok(days) if (1..=MAX_SUSPENSION_DAYS).contains(&days) => {
+                    Some(env.now() + days * 24 * 60 * 60 * 1000)
+                }
+                _ => return HttpResponse::error("Bad request, invalid ban length.", 400),
+            },
+        };
+        let ban = user_obj::Ban {
+            until,
+            reason: reason
+                .map(str::trim)
+                .filter(|reason| !reason.is_empty())
+                .map(str::to_string),
+        };
+        set_ban(env, &target, Some(ban)).await?;
+        audit::record(
+            env,
+            &user,
+            AuditAction::BanUser(until),
+            &target.user_id,
+            reason,
+        )
+        .await?;
+        return HttpResponse::redirect("/?account");
     }
 
     if hashmap.contains_key("delete") {
@@ -103,73 +300,401 @@ pub async fn handle_post_request<S: AsRef<str>>(
             Some(post) => {
                 if post.post.user == user.user_id {
                     delete_post(env, post_id).await?;
+                    audit::record(env, &user, AuditAction::DeletePost, post_id, reason).await?;
 
-                    let mut headers = Headers::new();
-                    let prev_post_id = &post_id[..if post_id.chars().count() > 0 {
-                        post_id.chars().count() - 1
-                    } else {
-                        0
-                    }];
-                    headers.set("Location", prev_post_id).unwrap();
-                    Ok(Response::empty()?.with_status(303).with_headers(headers))
+                    // The tombstone stays in place, so return to it
+                    HttpResponse::redirect(path)
+                } else if user.can_moderate() {
+                    moderate(env, &user, post_id, AuditAction::RemovePost, reason).await?;
+                    HttpResponse::redirect(path)
                 } else {
-                    Response::error("Error: Insufficient permissions", 400)
+                    HttpResponse::error("Error: Insufficient permissions", 400)
                 }
             }
-            None => Response::error("Error: Invalid post", 400),
+            None => HttpResponse::error("Error: Invalid post", 400),
         };
     }
 
-    // unpack form data and ensure that the correct attributes exist.
-    if let Some(FormEntry::Field(title)) = form_data.get("title") {
-        if let Some(FormEntry::Field(content)) = form_data.get("content") {
-            // Assemble full title from old title and new char
-            let fulltitle = format!("{}{}", post_id, title);
+    if hashmap.contains_key("report") {
+        let reason = match form_data.get("reason") {
+            Some(reason) if REPORT_REASONS.contains(&reason.as_str()) => reason,
+            _ => return HttpResponse::error("Bad request, invalid report reason.", 400),
+        };
+        return match get_content(env, post_id).await? {
+            // Removed posts have already been dealt with by a moderator
+            Some(post) if post.post.deleted || post.post.removed => {
+                HttpResponse::error("Error: Deleted posts cannot be reported", 400)
+            }
+            Some(_) => {
+                report_post(env, post_id, &user.user_id, reason).await?;
+                render_message(
+                    "Thanks for your report",
+                    &format!(
+                        "A moderator will review <a href=\"/{}\">the post</a>.",
+                        post_id
+                    ),
+                )
+            }
+            None => HttpResponse::error("Error: Invalid post", 400),
+        };
+    }
+
+    if hashmap.contains_key("dismiss") {
+        if !user.can_moderate() {
+            return HttpResponse::error("Error: Insufficient permissions", 400);
+        }
+        return match clear_reports(env, post_id).await? {
+            Some(reports) => {
+                // The reports were unfounded, so show the post again if they hid it
+                if reports.hidden {
+                    set_removed(env, post_id, false).await?;
+                }
+                audit::record(env, &user, AuditAction::DismissReports, post_id, reason).await?;
+                HttpResponse::redirect("/?reports")
+            }
+            None => HttpResponse::error("Error: Post has no open reports", 404),
+        };
+    }
 
-            // Ensure title is one char
-            if title.len() != 1 {
-                return Response::error("Error: Only one char can be added at a time", 400);
+    for (query, action) in [
+        ("restore", AuditAction::RestorePost),
+        ("lock", AuditAction::LockPost),
+        ("unlock", AuditAction::UnlockPost),
+    ] {
+        if hashmap.contains_key(query) {
+            if !user.can_moderate() {
+                return HttpResponse::error("Error: Insufficient permissions", 400);
             }
+            return match get_content(env, post_id).await? {
+                Some(_) => {
+                    moderate(env, &user, post_id, action, reason).await?;
+                    HttpResponse::redirect(path)
+                }
+                None => HttpResponse::error("Error: Invalid post", 400),
+            };
+        }
+    }
+
+    if hashmap.contains_key("purge") {
+        return match get_content(env, post_id).await? {
+            // Purging would also clear the reports the moderator acted on
+            Some(post) if post.post.removed => {
+                HttpResponse::error("Error: Removed posts cannot be purged", 400)
+            }
+            Some(post) => {
+                if post.post.user == user.user_id {
+                    if purge_post(env, post_id).await? {
+                        audit::record(env, &user, AuditAction::PurgePost, post_id, reason).await?;
+                        let prev_post_id = get_parent_id(post_id).unwrap_or("");
+                        HttpResponse::redirect(format!("/{}", prev_post_id))
+                    } else {
+                        HttpResponse::error("Error: Only posts without replies can be purged", 409)
+                    }
+                } else {
+                    HttpResponse::error("Error: Insufficient permissions", 400)
+                }
+            }
+            None => HttpResponse::error("Error: Invalid post", 400),
+        };
+    }
 
-            // Ensure Ensure title is a valid char
-            if !title.contains(validchar) {
-                return Response::error("Char must be alphanumeric", 400);
+    if hashmap.contains_key("edit") {
+        return match get_content(env, post_id).await? {
+            Some(post) if post.post.deleted => {
+                HttpResponse::error("Error: Deleted posts cannot be edited", 400)
+            }
+            Some(post) if post.post.removed => {
+                HttpResponse::error("Error: Removed posts cannot be edited", 400)
+            }
+            Some(post) => {
+                if post.post.user == user.user_id {
+                    match form_data.get("content") {
+                        Some(content) => {
+                            let filtered = match filter_content(env, content).await? {
+                                Ok(filtered) => filtered,
+                                Err(message) => return HttpResponse::error(message, 400),
+                            };
+                            edit_post(env, post_id, &filtered.content).await?;
+                            flag_post(env, post_id, &filtered.flags).await?;
+                            audit::record(env, &user, AuditAction::EditPost, post_id, reason)
+                                .await?;
+                            HttpResponse::redirect(path)
+                        }
+                        None => HttpResponse::error("Bad request, content must be present.", 400),
+                    }
+                } else {
+                    HttpResponse::error("Error: Insufficient permissions", 400)
+                }
             }
+            None => HttpResponse::error("Error: Invalid post", 400),
+        };
+    }
 
-            // Ensure path exists
-            if get_content(env, post_id).await?.is_none() {
-                return Response::error("Error: Can only reply to a post that exists", 400);
+    if hashmap.contains_key("vote") {
+        let vote = match form_data.get("vote").map(|vote| vote.parse::<i8>()) {
+            Some(Ok(vote)) if (-1..=1).contains(&vote) => vote,
+            _ => return HttpResponse::error("Bad request, vote must be 1, 0 or -1.", 400),
+        };
+        return match get_content(env, post_id).await? {
+            Some(post) if post.post.deleted || post.post.removed => {
+                HttpResponse::error("Error: Deleted posts cannot be voted on", 400)
+            }
+            Some(_) => {
+                vote_post(env, post_id, &user.user_id, vote).await?;
+                HttpResponse::redirect(path)
             }
-            // Ensure fulltitle doesn't exist
-            if get_content(env, fulltitle.as_str()).await?.is_some() {
-                return Response::error("Error: post already exists", 409);
+            None => HttpResponse::error("Error: Invalid post", 400),
+        };
+    }
+
+    // unpack form data and ensure that the correct attributes exist.
+    if let Some(title) = form_data.get("title") {
+        if let Some(content) = form_data.get("content") {
+            // Assemble full title from old title and new char
+            let fulltitle = format!("{}{}", post_id, title);
+
+            if let Some(error) = validate_reply(env, post_id, title).await? {
+                return HttpResponse::error(error.message(), error.status());
             }
-            // Ensure total length is <= 512
-            if fulltitle.len() >= 512 {
-                return Response::error("Error: max length has been reached", 400);
+            let filtered = match filter_content(env, content).await? {
+                Ok(filtered) => filtered,
+                Err(message) => return HttpResponse::error(message, 400),
+            };
+            // Only posts that pass the filters count against the limits
+            if let Some(retry_after) =
+                take_post_rate_limit(env, &user.user_id, req.client().ip.as_deref()).await?
+            {
+                return Ok(HttpResponse::error(POSTING_TOO_QUICKLY, 429)?
+                    .with_header("Retry-After", retry_after.to_string()));
             }
 
             // actually save new post content
-            post_content(env, fulltitle.as_str(), content.as_str(), user).await?;
+            post_content(env, fulltitle.as_str(), filtered.content.as_str(), user).await?;
+            flag_post(env, &fulltitle, &filtered.flags).await?;
+
+            // redirect user to new page
+            return HttpResponse::redirect(format!("/{}", fulltitle));
+        }
+    }
+    HttpResponse::error("Bad request, title and content must both be present.", 400)
+}
+
+/*
+ * Carry out a moderator's action on a post and record it in the audit log
+ * */
+async fn moderate<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    post_id: &str,
+    action: AuditAction,
+    reason: Option<&str>,
+) -> Result<()> {
+    match action {
+        AuditAction::RemovePost | AuditAction::RestorePost => {
+            set_removed(env, post_id, action == AuditAction::RemovePost).await?;
+            // Either way a moderator has now reviewed the post
+            clear_reports(env, post_id).await?;
+        }
+        AuditAction::LockPost => set_locked(env, post_id, true).await?,
+        AuditAction::UnlockPost => set_locked(env, post_id, false).await?,
+        _ => unreachable!("{} is not a moderator's action on a post", action.name()),
+    }
+    audit::record(env, user, action, post_id, reason).await
+}
+
+/*
+ * Reasons a reply can be rejected, shared by the html forms and the json api
+ * */
+pub enum ReplyError {
+    TitleLength,
+    InvalidChar,
+    MissingParent,
+    AlreadyExists,
+    MaxLength,
+    Locked,
+}
 
-            // create reponse to redirect user to new page
-            let response = Response::empty()?;
-            let mut headers = Headers::new();
-            headers.set("Location", format!("/{}", fulltitle).as_str())?;
+impl ReplyError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ReplyError::TitleLength => "Error: Only one char can be added at a time",
+            ReplyError::InvalidChar => "Char must be alphanumeric",
+            ReplyError::MissingParent => "Error: Can only reply to a post that exists",
+            ReplyError::AlreadyExists => "Error: post already exists",
+            ReplyError::MaxLength => "Error: max length has been reached",
+            ReplyError::Locked => "Error: replies to this thread are locked",
+        }
+    }
 
-            return Ok(response.with_status(303).with_headers(headers));
+    pub fn status(&self) -> u16 {
+        match self {
+            ReplyError::AlreadyExists => 409,
+            ReplyError::Locked => 403,
+            _ => 400,
         }
     }
-    Response::error("Bad request, title and content must both be present.", 400)
+}
+
+/*
+ * Longest a suspension can be, anything longer is a permanent ban
+ * */
+const MAX_SUSPENSION_DAYS: u64 = 365;
+
+pub const POSTING_TOO_QUICKLY: &str =
+    "Error: you're posting too quickly, wait a while and try again";
+
+/*
+ * Count a new post against the posting limits for the user and their address, returning the
+ * seconds until they can post again if they have reached one. Limits are set by the
+ * {IP_,}POST_LIMIT_PER_{MINUTE,DAY} vars, any that aren't set don't apply
+ * */
+pub async fn take_post_rate_limit<E: Backend>(
+    env: &E,
+    user_id: &str,
+    ip: Option<&str>,
+) -> Result<Option<u64>> {
+    let user_key = format!("post:user:{}", user_id);
+    let user_limits = post_rate_limits(env, "POST_LIMIT");
+    let mut keys = vec![(user_key.as_str(), user_limits.as_slice())];
+    // Checked together, so a post refused by the ip limit isn't counted against the user
+    let ip_key = ip.map(|ip| format!("post:ip:{}", ip));
+    let ip_limits = post_rate_limits(env, "IP_POST_LIMIT");
+    if let Some(ip_key) = &ip_key {
+        keys.push((ip_key.as_str(), ip_limits.as_slice()));
+    }
+    throttle::take(env, &keys).await
+}
+
+/*
+ * Run new content through the configured content filters, returning it as it should be saved or
+ * the message saying why it was rejected
+ * */
+pub async fn filter_content<E: Backend>(
+    env: &E,
+    content: &str,
+) -> Result<std::result::Result<Filtered, String>> {
+    let filters = get_filter_config(env).await?.filters();
+    Ok(content_filter::run(&filters, content))
+}
+
+/*
+ * Report a post that the filters flagged once it is saved, so that moderators review it
+ * */
+pub async fn flag_post<E: Backend>(env: &E, post_id: &str, flags: &[String]) -> Result<()> {
+    if !flags.is_empty() {
+        report_post(env, post_id, FILTER_REPORTER, &flags.join(", ")).await?;
+    }
+    Ok(())
+}
+
+/*
+ * Who filter reports are made as, there is one per post like any other reporter
+ * */
+const FILTER_REPORTER: &str = "filter";
+
+fn post_rate_limits<E: Backend>(env: &E, prefix: &str) -> Vec<throttle::RateLimit> {
+    [("PER_MINUTE", 60), ("PER_DAY", 60 * 60 * 24)]
+        .iter()
+        .filter_map(|(name, period)| {
+            let count = env.var(&format!("{}_{}", prefix, name)).ok()?;
+            Some(throttle::RateLimit {
+                count: count.parse().ok()?,
+                period: *period,
+            })
+        })
+        .collect()
+}
+
+pub async fn validate_reply<E: Backend>(
+    env: &E,
+    post_id: &str,
+    title: &str,
+) -> Result<Option<ReplyError>> {
+    // Assemble full title from old title and new char
+    let fulltitle = format!("{}{}", post_id, title);
+
+    // Ensure title is one char
+    if title.len() != 1 {
+        return Ok(Some(ReplyError::TitleLength));
+    }
+
+    // Ensure Ensure title is a valid char
+    if !title.contains(validchar) {
+        return Ok(Some(ReplyError::InvalidChar));
+    }
+
+    // Ensure path exists
+    if get_content(env, post_id).await?.is_none() {
+        return Ok(Some(ReplyError::MissingParent));
+    }
+    // Ensure nothing above it has been locked by a moderator
+    if get_lock(env, post_id).await?.is_some() {
+        return Ok(Some(ReplyError::Locked));
+    }
+    // Ensure fulltitle doesn't exist
+    if get_content(env, fulltitle.as_str()).await?.is_some() {
+        return Ok(Some(ReplyError::AlreadyExists));
+    }
+    // Ensure total length is <= 512
+    if fulltitle.len() >= 512 {
+        return Ok(Some(ReplyError::MaxLength));
+    }
+    Ok(None)
 }
 
 fn validchar(c: char) -> bool {
-    ('0'..='9').contains(&c) || ('a'..='z').contains(&c) || ('A'..='Z').contains(&c)
+    c.is_ascii_alphanumeric()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::db::memory::MemoryBackend;
+    use crate::db::store::Store;
+    use crate::harness::{emailed_token, register, session_from_cookie, TestRequest};
+    use futures::executor::block_on;
+
+    /*
+     * Backend with a root post and a reply "a", both written by a@example.com
+     * */
+    fn setup() -> (MemoryBackend, String) {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "a");
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        block_on(post_content(&env, "", "root", user)).unwrap();
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        block_on(post_content(&env, "a", "reply", user)).unwrap();
+        (env, session_id)
+    }
+
+    fn exists(env: &MemoryBackend, post_id: &str) -> bool {
+        block_on(get_content(env, post_id)).unwrap().is_some()
+    }
+
+    /*
+     * Register a user and make them a moderator, returning their session id
+     * */
+    fn moderator(env: &MemoryBackend, email: &str, username: &str) -> String {
+        let session_id = register(env, email, username);
+        let user = block_on(get_session(env, &session_id)).unwrap().unwrap();
+        block_on(set_role(env, &user, user_obj::Role::Moderator)).unwrap();
+        session_id
+    }
+
+    /*
+     * Every entry in the audit log, newest first
+     * */
+    fn audit_log(env: &MemoryBackend) -> Vec<crate::audit_obj::AuditEntry> {
+        let kv = env.store("USERS").unwrap();
+        block_on(async {
+            let mut entries = Vec::new();
+            for key in kv.list("audit:").await.unwrap() {
+                let entry = kv.get(&key).await.unwrap().unwrap();
+                entries.push(serde_json::from_str(&entry).unwrap());
+            }
+            entries
+        })
+    }
 
     #[test]
     fn valid_char() {
@@ -181,4 +706,1155 @@ mod test {
         assert!(!validchar('#'), "# is not valid char");
         assert!(!validchar('~'), "~ is not valid char");
     }
+
+    #[test]
+    fn login_sets_session_cookie() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/a")
+            .query("login")
+            .form("email", "a@example.com")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/a"));
+        let session_id = session_from_cookie(&response).unwrap();
+        assert!(!session_id.is_empty());
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_some());
+        assert!(response.header("Set-Cookie").unwrap().contains("HttpOnly"));
+    }
+
+    #[test]
+    fn repeated_failed_logins_are_throttled() {
+        let (env, _) = setup();
+        let login = || {
+            TestRequest::post("/a")
+                .query("login")
+                .header("CF-Connecting-IP", "192.0.2.1")
+                .form("email", "a@example.com")
+                .form("password", "wrong")
+                .send(&env)
+        };
+        for _ in 0..5 {
+            assert_eq!(login().status, 200);
+        }
+        let response = login();
+        assert_eq!(response.status, 429);
+        assert_eq!(response.header("Retry-After"), Some("30"));
+        assert!(response.body.contains("login-error"));
+        assert!(response
+            .body
+            .contains(LoginError::TooManyAttempts(30).message()));
+    }
+
+    #[test]
+    fn login_wrong_password_renders_error() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/a")
+            .query("login")
+            .form("email", "a@example.com")
+            .form("password", "wrong")
+            .send(&env);
+        assert_eq!(response.status, 200);
+        assert!(response.header("Set-Cookie").is_none());
+        assert!(response.body.contains("login-error"));
+    }
+
+    #[test]
+    fn login_missing_fields() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/a")
+            .query("login")
+            .form("email", "a@example.com")
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn login_takes_priority_over_register() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/a")
+            .query("login")
+            .query("register")
+            .form("email", "b@example.com")
+            .form("username", "b")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 200);
+        assert!(block_on(get_user_by_email(&env, "b@example.com"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn register_creates_pending_user() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/")
+            .query("register")
+            .form("email", "b@example.com")
+            .form("username", "b")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 200);
+        assert!(response.header("Set-Cookie").is_none());
+        assert!(response.body.contains("b@example.com"));
+        assert!(block_on(get_user_by_email(&env, "b@example.com"))
+            .unwrap()
+            .is_some());
+
+        let login = || {
+            TestRequest::post("/")
+                .query("login")
+                .form("email", "b@example.com")
+                .form("password", "password")
+                .send(&env)
+        };
+        let response = login();
+        assert!(response.header("Set-Cookie").is_none());
+        assert!(response.body.contains(LoginError::Unverified.message()));
+
+        // Follow the link from the email
+        assert_eq!(env.outbox()[0].to, "b@example.com");
+        let token = emailed_token(&env);
+        let response = TestRequest::get("/verify")
+            .query_value("token", &token)
+            .send(&env);
+        assert_eq!(response.status, 200);
+        let response = TestRequest::get("/verify")
+            .query_value("token", &token)
+            .send(&env);
+        assert_eq!(response.status, 400);
+
+        assert_eq!(login().status, 303);
+    }
+
+    #[test]
+    fn register_existing_email_renders_error() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/")
+            .query("register")
+            .form("email", "a@example.com")
+            .form("username", "b")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("login-error"));
+    }
+
+    #[test]
+    fn register_missing_fields() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/")
+            .query("register")
+            .form("email", "b@example.com")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn posting_is_rate_limited() {
+        let (mut env, session_id) = setup();
+        env.set_var("POST_LIMIT_PER_MINUTE", "2");
+        env.set_var("IP_POST_LIMIT_PER_MINUTE", "3");
+        let reply = |session_id: &str, title: &str| {
+            TestRequest::post("/a")
+                .cookie("sessionId", session_id)
+                .header("CF-Connecting-IP", "192.0.2.1")
+                .form("title", title)
+                .form("content", "content")
+                .send(&env)
+        };
+        assert_eq!(reply(&session_id, "b").status, 303);
+        assert_eq!(reply(&session_id, "c").status, 303);
+        let response = reply(&session_id, "d");
+        assert_eq!(response.status, 429);
+        assert!(response.header("Retry-After").is_some());
+        assert!(!exists(&env, "ad"));
+
+        // Another account from the same address is held to the address's limit
+        let other_session_id = register(&env, "b@example.com", "b");
+        assert_eq!(reply(&other_session_id, "d").status, 303);
+        assert_eq!(reply(&other_session_id, "e").status, 429);
+        // The post the address's limit refused didn't count against the account
+        let response = TestRequest::post("/a")
+            .cookie("sessionId", &other_session_id)
+            .header("CF-Connecting-IP", "192.0.2.2")
+            .form("title", "e")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 303);
+    }
+
+    #[test]
+    fn logged_out_user_cannot_post() {
+        let (env, _) = setup();
+        let response = TestRequest::post("/a")
+            .form("title", "b")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 401);
+
+        let response = TestRequest::post("/a")
+            .cookie("sessionId", "invalid")
+            .form("title", "b")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 401);
+    }
+
+    #[test]
+    fn logout_deletes_session() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .query("logout")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/a"));
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_none());
+    }
+
+    #[test]
+    fn delete_own_post() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .query("delete")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/a"));
+        let post = block_on(get_content(&env, "a")).unwrap().unwrap();
+        assert!(post.post.deleted);
+
+        let response = TestRequest::post("/a")
+            .query("edit")
+            .cookie("sessionId", &session_id)
+            .form("content", "edited")
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn purge_own_post() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/")
+            .query("purge")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 409);
+        assert!(exists(&env, ""));
+
+        let response = TestRequest::post("/a")
+            .query("purge")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/"));
+        assert!(!exists(&env, "a"));
+    }
+
+    #[test]
+    fn purge_rejected() {
+        let (env, _) = setup();
+        let session_id = register(&env, "b@example.com", "b");
+        let response = TestRequest::post("/a")
+            .query("purge")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 400);
+        assert!(exists(&env, "a"));
+
+        let response = TestRequest::post("/b")
+            .query("purge")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn posts_need_the_sessions_csrf_token() {
+        let (env, session_id) = setup();
+        let page = TestRequest::get("/a")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        let token = csrf::token(&env, &session_id).unwrap();
+        assert!(page
+            .body
+            .contains(&format!("name=\"csrf\" value=\"{}\"", token)));
+
+        let other_session_id = register(&env, "b@example.com", "b");
+        let other_token = csrf::token(&env, &other_session_id).unwrap();
+        for token in &["", "wrong", other_token.as_str()] {
+            let response = TestRequest::post("/a")
+                .query("delete")
+                .cookie("sessionId", &session_id)
+                .form("csrf", token)
+                .send(&env);
+            assert_eq!(response.status, 403);
+        }
+        assert!(
+            !block_on(get_content(&env, "a"))
+                .unwrap()
+                .unwrap()
+                .post
+                .deleted
+        );
+    }
+
+    #[test]
+    fn cross_site_posts_are_refused() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .query("delete")
+            .cookie("sessionId", &session_id)
+            .header("Host", "example.com")
+            .header("Origin", "https://evil.example")
+            .send(&env);
+        assert_eq!(response.status, 403);
+        assert!(
+            !block_on(get_content(&env, "a"))
+                .unwrap()
+                .unwrap()
+                .post
+                .deleted
+        );
+
+        let response = TestRequest::post("/a")
+            .query("login")
+            .header("Host", "example.com")
+            .header("Referer", "https://evil.example/login")
+            .form("email", "a@example.com")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 403);
+
+        let response = TestRequest::post("/a")
+            .query("login")
+            .header("Host", "example.com")
+            .header("Origin", "https://example.com")
+            .form("email", "a@example.com")
+            .form("password", "password")
+            .send(&env);
+        assert_eq!(response.status, 303);
+    }
+
+    #[test]
+    fn delete_other_users_post() {
+        let (env, _) = setup();
+        let session_id = register(&env, "b@example.com", "b");
+        let response = TestRequest::post("/a")
+            .query("delete")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 400);
+        assert!(exists(&env, "a"));
+    }
+
+    #[test]
+    fn delete_missing_post() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/b")
+            .query("delete")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn reply_creates_post() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .cookie("sessionId", &session_id)
+            .form("title", "b")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/ab"));
+        assert!(exists(&env, "ab"));
+    }
+
+    #[test]
+    fn reply_rejects_invalid_titles() {
+        let (env, session_id) = setup();
+        for title in &["", "bc", "$"] {
+            let response = TestRequest::post("/a")
+                .cookie("sessionId", &session_id)
+                .form("title", title)
+                .form("content", "content")
+                .send(&env);
+            assert_eq!(response.status, 400, "title {:?} should be rejected", title);
+        }
+    }
+
+    #[test]
+    fn reply_to_missing_post() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/b")
+            .cookie("sessionId", &session_id)
+            .form("title", "c")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 400);
+        assert!(!exists(&env, "bc"));
+    }
+
+    #[test]
+    fn reply_to_existing_title() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/")
+            .cookie("sessionId", &session_id)
+            .form("title", "a")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 409);
+    }
+
+    #[test]
+    fn reply_max_length() {
+        let (env, session_id) = setup();
+        let post_id = "a".repeat(511);
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        block_on(post_content(&env, &post_id, "", user)).unwrap();
+        let response = TestRequest::post(&format!("/{}", post_id))
+            .cookie("sessionId", &session_id)
+            .form("title", "a")
+            .form("content", "content")
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn reply_missing_fields() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .cookie("sessionId", &session_id)
+            .form("title", "b")
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn only_get_and_post_are_allowed() {
+        let (env, _) = setup();
+        let response = TestRequest::get("/a").method(Method::Put).send(&env);
+        assert_eq!(response.status, 405);
+    }
+
+    #[test]
+    fn edit_own_post() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .query("edit")
+            .cookie("sessionId", &session_id)
+            .form("content", "edited")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/a"));
+        let post = block_on(get_content(&env, "a")).unwrap().unwrap();
+        assert_eq!(post.post.content, "edited");
+        assert_eq!(block_on(get_revisions(&env, "a")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn edit_rejected() {
+        let (env, session_id) = setup();
+        let other_session_id = register(&env, "b@example.com", "b");
+        let response = TestRequest::post("/a")
+            .query("edit")
+            .cookie("sessionId", &other_session_id)
+            .form("content", "edited")
+            .send(&env);
+        assert_eq!(response.status, 400);
+
+        let response = TestRequest::post("/a")
+            .query("edit")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 400);
+
+        let response = TestRequest::post("/b")
+            .query("edit")
+            .cookie("sessionId", &session_id)
+            .form("content", "edited")
+            .send(&env);
+        assert_eq!(response.status, 400);
+        assert!(block_on(get_revisions(&env, "a")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn vote_on_post() {
+        let (env, session_id) = setup();
+        let other_session_id = register(&env, "b@example.com", "b");
+        for session_id in &[&session_id, &other_session_id] {
+            let response = TestRequest::post("/a")
+                .query("vote")
+                .cookie("sessionId", session_id)
+                .form("vote", "1")
+                .send(&env);
+            assert_eq!(response.status, 303);
+            assert_eq!(response.header("Location"), Some("/a"));
+        }
+        let post = block_on(get_content(&env, "a")).unwrap().unwrap();
+        assert_eq!(post.post.score, 2);
+
+        // The page offers to take the vote back
+        let response = TestRequest::get("/a")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert!(response.body.contains("2 points"));
+        assert!(response.body.contains("class=\"voted\" value=\"0\""));
+    }
+
+    #[test]
+    fn vote_rejected() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/a")
+            .query("vote")
+            .form("vote", "1")
+            .send(&env);
+        assert_eq!(response.status, 401);
+
+        for vote in &["2", "up", ""] {
+            let response = TestRequest::post("/a")
+                .query("vote")
+                .cookie("sessionId", &session_id)
+                .form("vote", vote)
+                .send(&env);
+            assert_eq!(response.status, 400);
+        }
+
+        let response = TestRequest::post("/b")
+            .query("vote")
+            .cookie("sessionId", &session_id)
+            .form("vote", "1")
+            .send(&env);
+        assert_eq!(response.status, 400);
+
+        block_on(delete_post(&env, "a")).unwrap();
+        let response = TestRequest::post("/a")
+            .query("vote")
+            .cookie("sessionId", &session_id)
+            .form("vote", "1")
+            .send(&env);
+        assert_eq!(response.status, 400);
+        let post = block_on(get_content(&env, "a")).unwrap().unwrap();
+        assert_eq!(post.post.score, 0);
+    }
+
+    #[test]
+    fn forgot_password_resets_by_email() {
+        let (env, session_id) = setup();
+        let response = TestRequest::get("/").query("forgot").send(&env);
+        assert!(response.body.contains("class=\"forgot\""));
+
+        // Unknown emails get the same response but no mail
+        let forgot = |email: &str| {
+            TestRequest::post("/")
+                .query("forgot")
+                .form("email", email)
+                .send(&env)
+        };
+        assert_eq!(forgot("b@example.com").status, 200);
+        assert!(env.outbox().is_empty());
+        assert_eq!(forgot("a@example.com").status, 200);
+        let token = emailed_token(&env);
+
+        let response = TestRequest::get("/reset")
+            .query_value("token", &token)
+            .send(&env);
+        assert!(response.body.contains(&token));
+
+        let reset = || {
+            TestRequest::post("/")
+                .query("reset")
+                .form("token", &token)
+                .form("password", "new password")
+                .send(&env)
+        };
+        assert_eq!(reset().status, 200);
+        assert_eq!(reset().status, 400);
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_none());
+        assert!(block_on(create_session(
+            &env,
+            "a@example.com",
+            "new password",
+            &Default::default()
+        ))
+        .unwrap()
+        .is_ok());
+    }
+
+    #[test]
+    fn account_page_changes_username_and_password() {
+        let (env, session_id) = setup();
+        assert_eq!(
+            TestRequest::get("/").query("account").send(&env).status,
+            401
+        );
+        let response = TestRequest::get("/")
+            .query("account")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("class=\"rename\""));
+
+        register(&env, "b@example.com", "b");
+        let rename = |username: &str| {
+            TestRequest::post("/")
+                .query("rename")
+                .cookie("sessionId", &session_id)
+                .form("username", username)
+                .send(&env)
+        };
+        let response = rename("B");
+        assert_eq!(response.status, 409);
+        assert!(response
+            .body
+            .contains(AccountError::UsernameTaken.message()));
+        let response = rename("alice");
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/?account"));
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        assert_eq!(user.account.username, "alice");
+
+        let change_password = |current: &str| {
+            TestRequest::post("/")
+                .query("password")
+                .cookie("sessionId", &session_id)
+                .form("current", current)
+                .form("password", "new password")
+                .send(&env)
+        };
+        assert_eq!(change_password("wrong").status, 403);
+        assert_eq!(change_password("password").status, 303);
+        assert!(block_on(create_session(
+            &env,
+            "a@example.com",
+            "new password",
+            &Default::default()
+        ))
+        .unwrap()
+        .is_ok());
+    }
+
+    #[test]
+    fn account_page_lists_and_revokes_sessions() {
+        let (env, session_id) = setup();
+        let response = TestRequest::post("/")
+            .query("login")
+            .header("User-Agent", "<b>phone</b>")
+            .form("email", "a@example.com")
+            .form("password", "password")
+            .send(&env);
+        let other_session_id = session_from_cookie(&response).unwrap();
+
+        let response = TestRequest::get("/")
+            .query("account")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert!(response.body.contains("&lt;b&gt;phone&lt;/b&gt;"));
+        assert!(response.body.contains("this device"));
+        assert!(!response.body.contains(&other_session_id));
+        let public_id = crate::crypto_helpers::public_id(&other_session_id);
+        assert!(response.body.contains(&public_id));
+
+        let revoke = |public_id: &str| {
+            TestRequest::post("/")
+                .query("revoke")
+                .cookie("sessionId", &session_id)
+                .form("session", public_id)
+                .send(&env)
+        };
+        assert_eq!(revoke(&public_id).status, 303);
+        assert_eq!(revoke(&public_id).status, 404);
+        assert!(block_on(get_session(&env, &other_session_id))
+            .unwrap()
+            .is_none());
+
+        let response = TestRequest::post("/")
+            .query("logoutall")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(session_from_cookie(&response), Some(String::new()));
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_none());
+    }
+
+    #[test]
+    fn moderators_remove_and_restore_posts() {
+        let (env, session_id) = setup();
+        block_on(edit_post(&env, "a", "spam")).unwrap();
+        let moderator_id = moderator(&env, "m@example.com", "m");
+        let response = TestRequest::post("/a")
+            .query("delete")
+            .cookie("sessionId", &moderator_id)
+            .form("reason", " spam ")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        let post = block_on(get_content(&env, "a")).unwrap().unwrap().post;
+        assert!(post.removed);
+        assert!(!post.deleted);
+        assert_eq!(post.content, "spam");
+
+        // Hidden from everyone but moderators, and the author can't edit, vote on or purge it
+        let page = |session_id: &str| {
+            TestRequest::get("/a")
+                .cookie("sessionId", session_id)
+                .send(&env)
+                .body
+        };
+        let body = page(&session_id);
+        assert!(body.contains("[removed]"));
+        assert!(!body.contains("spam"));
+        assert!(!body.contains("class=\"moderate\""));
+        let body = page(&moderator_id);
+        assert!(body.contains("[removed]") && body.contains("spam"));
+        assert!(body.contains("?restore"));
+        for query in &["edit", "vote", "purge"] {
+            let response = TestRequest::post("/a")
+                .query(query)
+                .cookie("sessionId", &session_id)
+                .form("content", "edited")
+                .form("vote", "1")
+                .send(&env);
+            assert_eq!(response.status, 400);
+        }
+        assert!(exists(&env, "a"));
+
+        env.advance(1);
+        let response = TestRequest::post("/a")
+            .query("restore")
+            .cookie("sessionId", &moderator_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert!(
+            !block_on(get_content(&env, "a"))
+                .unwrap()
+                .unwrap()
+                .post
+                .removed
+        );
+
+        let log = audit_log(&env);
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].action, AuditAction::RestorePost);
+        assert_eq!(log[0].reason, None);
+        assert_eq!(log[1].action, AuditAction::RemovePost);
+        assert_eq!(log[1].target, "a");
+        assert_eq!(log[1].reason.as_deref(), Some("spam"));
+    }
+
+    #[test]
+    fn moderation_needs_a_moderator() {
+        let (env, _) = setup();
+        let session_id = register(&env, "b@example.com", "b");
+        for query in &["restore", "lock", "unlock", "role"] {
+            let response = TestRequest::post("/a")
+                .query(query)
+                .cookie("sessionId", &session_id)
+                .form("username", "b")
+                .form("role", "admin")
+                .send(&env);
+            assert_eq!(response.status, 400, "{} should be refused", query);
+        }
+        assert!(block_on(get_lock(&env, "a")).unwrap().is_none());
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        assert_eq!(user.account.role, user_obj::Role::User);
+        assert!(audit_log(&env).is_empty());
+    }
+
+    #[test]
+    fn locked_threads_refuse_replies() {
+        let (env, session_id) = setup();
+        let moderator_id = moderator(&env, "m@example.com", "m");
+        let moderate = |query: &str| {
+            TestRequest::post("/a")
+                .query(query)
+                .cookie("sessionId", &moderator_id)
+                .send(&env)
+        };
+        let reply = |post_id: &str, title: &str| {
+            TestRequest::post(post_id)
+                .cookie("sessionId", &session_id)
+                .form("title", title)
+                .form("content", "content")
+                .send(&env)
+        };
+        assert_eq!(moderate("lock").status, 303);
+        assert_eq!(reply("/a", "b").status, 403);
+        assert!(!exists(&env, "ab"));
+        // Only the locked subtree
+        assert_eq!(reply("/", "b").status, 303);
+
+        let body = TestRequest::get("/a")
+            .cookie("sessionId", &session_id)
+            .send(&env)
+            .body;
+        assert!(body.contains("class=\"locked\""));
+        assert!(!body.contains("class=\"user-subpost\""));
+
+        env.advance(1);
+        assert_eq!(moderate("unlock").status, 303);
+        assert_eq!(reply("/a", "b").status, 303);
+        let actions: Vec<_> = audit_log(&env).iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, [AuditAction::UnlockPost, AuditAction::LockPost]);
+    }
+
+    #[test]
+    fn admins_change_roles() {
+        let (mut env, session_id) = setup();
+        env.set_var("ADMIN_EMAILS", "admin@example.com");
+        let admin_id = register(&env, "admin@example.com", "boss");
+        let change_role = |session_id: &str, username: &str, role: &str| {
+            TestRequest::post("/")
+                .query("role")
+                .cookie("sessionId", session_id)
+                .form("username", username)
+                .form("role", role)
+                .send(&env)
+        };
+        assert_eq!(change_role(&admin_id, "A", "moderator").status, 303);
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        assert_eq!(user.account.role, user_obj::Role::Moderator);
+
+        assert_eq!(change_role(&admin_id, "boss", "user").status, 400);
+        assert_eq!(change_role(&admin_id, "nobody", "user").status, 404);
+        assert_eq!(change_role(&admin_id, "a", "owner").status, 400);
+        // Moderators can't make themselves admins
+        assert_eq!(change_role(&session_id, "a", "admin").status, 400);
+
+        let account = |session_id: &str| {
+            TestRequest::get("/")
+                .query("account")
+                .cookie("sessionId", session_id)
+                .send(&env)
+                .body
+        };
+        assert!(account(&admin_id).contains("class=\"change-role\""));
+        assert!(account(&session_id).contains("<span class=\"role\">moderator</span>"));
+        assert!(!account(&session_id).contains("class=\"change-role\""));
+
+        let log = audit_log(&env);
+        assert_eq!(log.len(), 1);
+        assert_eq!(
+            log[0].action,
+            AuditAction::ChangeRole(user_obj::Role::Moderator)
+        );
+        assert_eq!(log[0].target, user.user_id);
+    }
+
+    #[test]
+    fn audit_log_page_lists_and_filters_entries() {
+        let (mut env, session_id) = setup();
+        env.set_var("ADMIN_EMAILS", "admin@example.com");
+        let admin_id = register(&env, "admin@example.com", "boss");
+        let response = TestRequest::post("/a")
+            .query("edit")
+            .cookie("sessionId", &session_id)
+            .form("content", "edited")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        env.advance(1);
+        let response = TestRequest::post("/a")
+            .query("delete")
+            .cookie("sessionId", &session_id)
+            .form("reason", "<i>oops</i>")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        let actions: Vec<_> = audit_log(&env).iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, [AuditAction::DeletePost, AuditAction::EditPost]);
+
+        let audit_page = |session_id: &str, action: &str| {
+            TestRequest::get("/")
+                .query("audit")
+                .query_value("action", action)
+                .cookie("sessionId", session_id)
+                .send(&env)
+        };
+        assert_eq!(audit_page(&session_id, "").status, 403);
+        let response = audit_page(&admin_id, "");
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("class=\"audit-entry\""));
+        assert!(response.body.contains("&lt;i&gt;oops&lt;/i&gt;"));
+        assert!(response.body.contains("href=\"/a\""));
+        // The filter is a GET form, so the token would end up in the url
+        assert!(response
+            .body
+            .contains("class=\"audit-filter\" method=\"GET\""));
+        assert!(!response.body.contains("name=\"csrf\""));
+        let response = audit_page(&admin_id, "edit");
+        assert_eq!(response.body.matches("class=\"audit-entry\"").count(), 1);
+        assert!(response.body.contains("<option value=\"edit\" selected>"));
+        let response = audit_page(&admin_id, "lock");
+        assert!(!response.body.contains("class=\"audit-entry\""));
+    }
+
+    #[test]
+    fn moderators_ban_and_unban_users() {
+        let (env, session_id) = setup();
+        let user_id = block_on(get_session(&env, &session_id))
+            .unwrap()
+            .unwrap()
+            .user_id;
+        let moderator_id = moderator(&env, "m@example.com", "m");
+        let ban = |query: &str, days: &str| {
+            TestRequest::post("/")
+                .query(query)
+                .cookie("sessionId", &moderator_id)
+                .form("username", "a")
+                .form("days", days)
+                .form("reason", "<b>spam</b>")
+                .send(&env)
+        };
+        let login = || {
+            TestRequest::post("/")
+                .query("login")
+                .form("email", "a@example.com")
+                .form("password", "password")
+                .send(&env)
+        };
+
+        assert_eq!(ban("ban", "0").status, 400);
+        assert_eq!(ban("ban", "7").status, 303);
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_none());
+        let response = login();
+        assert_eq!(response.status, 403);
+        assert!(response.body.contains("banned until"));
+        assert!(response.body.contains("&lt;b&gt;spam&lt;/b&gt;"));
+        assert_eq!(session_from_cookie(&response), None);
+
+        env.advance(1);
+        assert_eq!(ban("unban", "").status, 303);
+        let session_id = session_from_cookie(&login()).unwrap();
+
+        // A session started while a ban is being made is ended on its next request
+        let mut user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        user.account.ban = Some(user_obj::Ban {
+            until: None,
+            reason: None,
+        });
+        let users_kv = env.store("USERS").unwrap();
+        block_on(users_kv.put(&user_id, &serde_json::to_string(&user.account).unwrap())).unwrap();
+        let response = TestRequest::get("/")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 403);
+        assert!(response.body.contains("banned permanently"));
+        assert_eq!(session_from_cookie(&response), Some(String::new()));
+        assert!(block_on(get_session(&env, &session_id)).unwrap().is_none());
+
+        let log = audit_log(&env);
+        assert_eq!(log[0].action, AuditAction::UnbanUser);
+        assert_eq!(log[0].target, user_id);
+        assert!(matches!(log[1].action, AuditAction::BanUser(Some(_))));
+    }
+
+    #[test]
+    fn suspensions_end() {
+        let (env, _) = setup();
+        let moderator_id = moderator(&env, "m@example.com", "m");
+        let response = TestRequest::post("/")
+            .query("ban")
+            .cookie("sessionId", &moderator_id)
+            .form("username", "a")
+            .form("days", "1")
+            .send(&env);
+        assert_eq!(response.status, 303);
+        let login = || {
+            TestRequest::post("/")
+                .query("login")
+                .form("email", "a@example.com")
+                .form("password", "password")
+                .send(&env)
+                .status
+        };
+        assert_eq!(login(), 403);
+        env.advance(24 * 60 * 60 * 1000);
+        assert_eq!(login(), 303);
+    }
+
+    #[test]
+    fn bans_need_a_higher_role() {
+        let (env, session_id) = setup();
+        let moderator_id = moderator(&env, "m@example.com", "m");
+        moderator(&env, "n@example.com", "n");
+        let ban = |session_id: &str, username: &str| {
+            TestRequest::post("/")
+                .query("ban")
+                .cookie("sessionId", session_id)
+                .form("username", username)
+                .send(&env)
+                .status
+        };
+        assert_eq!(ban(&session_id, "m"), 400);
+        assert_eq!(ban(&moderator_id, "n"), 400);
+        assert_eq!(ban(&moderator_id, "m"), 400);
+        assert_eq!(ban(&moderator_id, "nobody"), 404);
+        assert!(audit_log(&env).is_empty());
+    }
+
+    #[test]
+    fn reported_posts_are_queued_for_moderators() {
+        let (mut env, session_id) = setup();
+        env.set_var("REPORT_HIDE_THRESHOLD", "2");
+        let moderator_id = moderator(&env, "m@example.com", "m");
+        let reader_id = register(&env, "b@example.com", "b");
+        let report = |session_id: &str, reason: &str| {
+            TestRequest::post("/a")
+                .query("report")
+                .cookie("sessionId", session_id)
+                .form("reason", reason)
+                .send(&env)
+                .status
+        };
+        let queue = |session_id: &str| {
+            TestRequest::get("/")
+                .query("reports")
+                .cookie("sessionId", session_id)
+                .send(&env)
+        };
+
+        // Every post on the page has a report form, but only for logged in users
+        let page = TestRequest::get("/")
+            .cookie("sessionId", &reader_id)
+            .send(&env);
+        assert_eq!(page.body.matches("?report\"").count(), 2);
+        assert!(!TestRequest::get("/").send(&env).body.contains("?report"));
+
+        assert_eq!(report(&reader_id, "rude"), 400);
+        assert_eq!(report(&reader_id, "spam"), 200);
+        assert!(
+            !block_on(get_content(&env, "a"))
+                .unwrap()
+                .unwrap()
+                .post
+                .removed
+        );
+        assert_eq!(queue(&reader_id).status, 403);
+        let response = queue(&moderator_id);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("1 report"));
+        assert!(response.body.contains("spam (1)"));
+
+        // Reaching the threshold hides the post until it is reviewed
+        assert_eq!(report(&session_id, "abuse"), 200);
+        assert!(
+            block_on(get_content(&env, "a"))
+                .unwrap()
+                .unwrap()
+                .post
+                .removed
+        );
+        assert!(queue(&moderator_id).body.contains("hidden automatically"));
+
+        let response = TestRequest::post("/a")
+            .query("dismiss")
+            .cookie("sessionId", &moderator_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("Location"), Some("/?reports"));
+        assert!(
+            !block_on(get_content(&env, "a"))
+                .unwrap()
+                .unwrap()
+                .post
+                .removed
+        );
+        assert!(queue(&moderator_id)
+            .body
+            .contains("There are no open reports"));
+        assert_eq!(audit_log(&env)[0].action, AuditAction::DismissReports);
+
+        // Removing a reported post closes its reports too
+        report(&reader_id, "spam");
+        let response = TestRequest::post("/a")
+            .query("delete")
+            .cookie("sessionId", &moderator_id)
+            .send(&env);
+        assert_eq!(response.status, 303);
+        assert!(block_on(crate::db::report::get_reports(&env, "a"))
+            .unwrap()
+            .is_none());
+        // and it can't be reported back into the queue
+        assert_eq!(report(&reader_id, "spam"), 400);
+        assert!(block_on(crate::db::report::get_reports(&env, "a"))
+            .unwrap()
+            .is_none());
+        let response = TestRequest::post("/a")
+            .query("dismiss")
+            .cookie("sessionId", &reader_id)
+            .send(&env);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn new_posts_go_through_the_content_filters() {
+        let (mut env, session_id) = setup();
+        env.set_var(
+            "CONTENT_FILTERS",
+            r#"{"banned_words": {"words": ["hecker"], "action": "rewrite"},
+                "max_links": {"max": 0, "action": "flag"},
+                "max_repeated": {"max": 3}}"#,
+        );
+        env.set_var("POST_LIMIT_PER_MINUTE", "1");
+        let reply = |title: &str, content: &str| {
+            TestRequest::post("/a")
+                .cookie("sessionId", &session_id)
+                .form("title", title)
+                .form("content", content)
+                .send(&env)
+        };
+
+        let response = reply("b", "heck!!!!");
+        assert_eq!(response.status, 400);
+        assert!(response.body.contains("at most 3 times"));
+        assert!(!exists(&env, "ab"));
+
+        // The rejected post didn't count against the limit
+        assert_eq!(reply("b", "Hecker, see http://a.example").status, 303);
+        let post = block_on(get_content(&env, "ab")).unwrap().unwrap().post;
+        assert_eq!(post.content, "******, see http://a.example");
+        let reports = block_on(crate::db::report::get_reports(&env, "ab"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reports.reports[0].reason, "filter: too many links");
+
+        // A record in the store takes over from the var
+        let posts_kv = env.store("POSTS").unwrap();
+        block_on(posts_kv.put("config:filters", "{}")).unwrap();
+        env.advance(60_000);
+        assert_eq!(reply("c", "heck!!!!").status, 303);
+        let post = block_on(get_content(&env, "ac")).unwrap().unwrap().post;
+        assert_eq!(post.content, "heck!!!!");
+    }
+
+    #[test]
+    fn usernames_are_escaped() {
+        let (env, session_id) = setup();
+        // Legacy accounts never had their usernames validated
+        let mut user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        user.account.username = "<b>a</b>".to_string();
+        let users_kv = env.store("USERS").unwrap();
+        block_on(users_kv.put(
+            &user.user_id,
+            &serde_json::to_string(&user.account).unwrap(),
+        ))
+        .unwrap();
+        block_on(report_post(&env, "a", "x", "spam")).unwrap();
+        let moderator_id = moderator(&env, "m@example.com", "m");
+
+        let pages = [
+            TestRequest::get("/a").cookie("sessionId", &session_id),
+            TestRequest::get("/").cookie("sessionId", &moderator_id),
+            TestRequest::get("/a").query("history"),
+            TestRequest::get("/")
+                .query("reports")
+                .cookie("sessionId", &moderator_id),
+        ];
+        for page in pages {
+            let body = page.send(&env).body;
+            assert!(!body.contains("<b>a</b>"));
+            assert!(body.contains("&lt;b&gt;a&lt;/b&gt;"));
+        }
+    }
 }
@@ -1,10 +1,106 @@
 use crate::user_obj;
 use serde::{Deserialize, Serialize};
 
+/*
+ * Bumped whenever stored posts gain fields that can't simply default, records written before
+ * versioning was introduced deserialize as version 0
+ * */
+pub const POST_SCHEMA_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Post {
     pub user: String,
     pub content: String,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+    #[serde(default)]
+    pub reply_count: u32,
+    #[serde(default)]
+    pub deleted: bool,
+    /*
+     * Hidden by a moderator, unlike deleting the content is kept so that it can be restored
+     * */
+    #[serde(default)]
+    pub removed: bool,
+    /*
+     * Sum of every user's vote, kept on the post so that it can be shown and sorted on without
+     * reading the votes
+     * */
+    #[serde(default)]
+    pub score: i64,
+}
+
+/*
+ * Stored as KV metadata on each post so that replies can be ordered from the key listing alone.
+ * Posts written before this have none and sort as the oldest, with no replies
+ * */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PostMetadata {
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub reply_count: u32,
+    #[serde(default)]
+    pub score: i64,
+}
+
+impl From<&Post> for PostMetadata {
+    fn from(post: &Post) -> Self {
+        PostMetadata {
+            created_at: post.created_at,
+            reply_count: post.reply_count,
+            score: post.score,
+        }
+    }
+}
+
+/*
+ * Order replies are listed in, chosen with ?sort=
+ * */
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum SortOrder {
+    #[default]
+    Alphabetical,
+    Newest,
+    Oldest,
+    MostReplies,
+    Top,
+}
+
+impl SortOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "alphabetical" => Some(SortOrder::Alphabetical),
+            "newest" => Some(SortOrder::Newest),
+            "oldest" => Some(SortOrder::Oldest),
+            "replies" => Some(SortOrder::MostReplies),
+            "top" => Some(SortOrder::Top),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Alphabetical => "alphabetical",
+            SortOrder::Newest => "newest",
+            SortOrder::Oldest => "oldest",
+            SortOrder::MostReplies => "replies",
+            SortOrder::Top => "top",
+        }
+    }
+}
+
+/*
+ * A previous version of a post's content, stored whenever the post is edited
+ * */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Revision {
+    pub timestamp: u64,
+    pub content: String,
 }
 
 pub struct PostTitle {
@@ -12,3 +108,82 @@ pub struct PostTitle {
     pub user: Option<user_obj::User>,
     pub post: Post,
 }
+
+/*
+ * A reply along with its own replies, for rendering threads
+ * */
+pub struct ReplyTree {
+    pub post: PostTitle,
+    pub replies: Vec<ReplyTree>,
+}
+
+/*
+ * Json representation of a post returned by the api, the author is shown by username only
+ * */
+#[derive(Serialize, Debug)]
+pub struct PostJson {
+    pub title: String,
+    pub author: Option<String>,
+    pub content: String,
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+    pub reply_count: u32,
+    pub score: i64,
+    pub deleted: bool,
+    pub removed: bool,
+}
+
+impl From<&PostTitle> for PostJson {
+    fn from(post: &PostTitle) -> Self {
+        PostJson {
+            title: post.title.to_string(),
+            author: post
+                .user
+                .as_ref()
+                .map(|user| user.account.username.to_string()),
+            // Content is stored escaped for the html pages
+            content: match post.post.removed {
+                true => String::new(),
+                false => html_escape::decode_html_entities(&post.post.content).to_string(),
+            },
+            created_at: post.post.created_at,
+            updated_at: post.post.updated_at,
+            reply_count: post.post.reply_count,
+            score: post.post.score,
+            deleted: post.post.deleted,
+            removed: post.post.removed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deserialize_legacy_post() {
+        let post: Post =
+            serde_json::from_str(r#"{"user":"a@example.com","content":"hi"}"#).unwrap();
+        assert_eq!(post.version, 0);
+        assert_eq!(post.created_at, None);
+        assert_eq!(post.updated_at, None);
+        assert_eq!(post.reply_count, 0);
+        assert_eq!(post.score, 0);
+        assert!(!post.deleted);
+        assert!(!post.removed);
+    }
+
+    #[test]
+    fn sort_order_names() {
+        for sort in &[
+            SortOrder::Alphabetical,
+            SortOrder::Newest,
+            SortOrder::Oldest,
+            SortOrder::MostReplies,
+            SortOrder::Top,
+        ] {
+            assert_eq!(SortOrder::from_name(sort.name()), Some(*sort));
+        }
+        assert_eq!(SortOrder::from_name("random"), None);
+    }
+}
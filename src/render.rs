@@ -1,14 +1,135 @@
+use crate::audit_obj;
+use crate::crypto_helpers;
+use crate::db::audit;
 use crate::db::post::*;
+use crate::db::report::{get_reports, list_reported};
+use crate::db::store::Backend;
+use crate::db::user::{get_user, get_user_by_username, list_sessions, verify_user};
+use crate::http::HttpResponse;
+use crate::post_obj;
+use crate::report_obj::REPORT_REASONS;
 use crate::user_obj;
+use crate::utils;
 use regex::Regex;
+use std::collections::HashMap;
 use worker::*;
 
-pub async fn render_page(
+/*
+ * Deepest thread that can be requested with ?depth=
+ * */
+const MAX_DEPTH: usize = 8;
+/*
+ * Roughly the most replies fetched for a single page, however deep the thread
+ * */
+const MAX_THREAD_POSTS: usize = 200;
+/*
+ * Replies shown per page unless ?limit= asks for a different amount, up to MAX_PAGE_SIZE
+ * */
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+/*
+ * Audit log entries shown per page
+ * */
+pub const AUDIT_PAGE_SIZE: u64 = 50;
+/*
+ * Most reported posts shown in the moderators' queue at once
+ * */
+const REPORT_QUEUE_SIZE: usize = 50;
+
+/*
+ * How the replies on a page are shown, parsed from the query string
+ * */
+pub struct ViewOptions {
+    pub depth: usize,
+    pub sort: post_obj::SortOrder,
+    pub limit: usize,
+    /*
+     * Where the current page of replies starts, None for the first page
+     * */
+    pub cursor: Option<String>,
+    /*
+     * Cursors of the pages before this one, so that there is a previous link to follow.
+     * The first page is an empty string
+     * */
+    pub history: Vec<String>,
+}
+
+impl ViewOptions {
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let depth = query
+            .get("depth")
+            .and_then(|depth| depth.parse::<usize>().ok())
+            .unwrap_or(1)
+            .clamp(1, MAX_DEPTH);
+        let sort = query
+            .get("sort")
+            .and_then(|sort| post_obj::SortOrder::from_name(sort))
+            .unwrap_or_default();
+        let limit = query
+            .get("limit")
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let cursor = query
+            .get("cursor")
+            .filter(|cursor| !cursor.is_empty())
+            .cloned();
+        let history = match query.get("prev") {
+            Some(prev) => prev.split(',').map(|cursor| cursor.to_string()).collect(),
+            None => Vec::new(),
+        };
+        ViewOptions {
+            depth,
+            sort,
+            limit,
+            cursor,
+            history,
+        }
+    }
+
+    /*
+     * Query string for the same view starting at another page
+     * */
+    fn page_query(&self, cursor: Option<&str>, history: &[String]) -> String {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if self.depth != 1 {
+            query.append_pair("depth", &self.depth.to_string());
+        }
+        if self.sort != post_obj::SortOrder::default() {
+            query.append_pair("sort", self.sort.name());
+        }
+        if self.limit != DEFAULT_PAGE_SIZE {
+            query.append_pair("limit", &self.limit.to_string());
+        }
+        if let Some(cursor) = cursor {
+            query.append_pair("cursor", cursor);
+        }
+        if !history.is_empty() {
+            query.append_pair("prev", &history.join(","));
+        }
+        query.finish()
+    }
+}
+
+impl Default for ViewOptions {
+    fn default() -> Self {
+        ViewOptions {
+            depth: 1,
+            sort: post_obj::SortOrder::default(),
+            limit: DEFAULT_PAGE_SIZE,
+            cursor: None,
+            history: Vec::new(),
+        }
+    }
+}
+
+pub async fn render_page<E: Backend>(
     path: &str,
-    env: &Env,
-    is_login_error: bool,
+    env: &E,
+    login_error: Option<&str>,
     user: Option<user_obj::User>,
-) -> Result<Response> {
+    options: &ViewOptions,
+) -> Result<HttpResponse> {
     let styles = [
         include_str!("html/style/login.css"),
         include_str!("html/style/layout.css"),
@@ -31,28 +152,29 @@ pub async fn render_page(
     // get content, return error if page doesn't exists
     let content = match get_content(env, post_id).await? {
         None => {
-            return Response::error("Page Not Found", 404);
+            return HttpResponse::error("Page Not Found", 404);
         }
         Some(content) => content,
     };
 
-    // get all replies to post
-    let replies = get_replies(env, post_id).await?;
+    // get a page of replies to post, down to the requested depth
+    let (replies, next_cursor) = get_subtree(
+        env,
+        post_id,
+        options.sort,
+        options.cursor.as_deref(),
+        options.limit,
+        options.depth,
+        MAX_THREAD_POSTS,
+    )
+    .await?;
 
-    // Render replies
-    let replies_html = replies
-        .iter()
-        .map(|post| {
-            let reply = include_str!("html/templates/post.html")
-                .replace("<!--title-->", post.title.as_str())
-                .replace("<!--content-->", post.post.content.as_str());
-            let user_text = match &post.user {
-                None => "[DELETED]",
-                Some(user) => user.account.username.as_str(),
-            };
-            reply.replace("<!--user-->", user_text)
-        })
-        .collect::<String>();
+    let now = env.now();
+    let lock = get_lock(env, post_id).await?;
+    let can_moderate = matches!(&user, Some(user) if user.can_moderate());
+
+    // Render replies, only logged in users can report them
+    let replies_html = render_replies(&replies, now, user.is_some());
     // render page
 
     let author_username = match &content.user {
@@ -67,20 +189,80 @@ pub async fn render_page(
     let mut response = include_str!("html/index.html")
         .replace("/*style*/", style.as_str())
         .replace("<!--title-->", post_id)
-        .replace("<!--content-->", content.post.content.as_str())
-        .replace("<!--author-->", author_username.as_ref())
+        .replace(
+            "<!--content-->",
+            render_post_content(&content.post, can_moderate).as_str(),
+        )
+        .replace("<!--locked-->", render_lock(lock.as_deref()).as_str())
+        .replace(
+            "<!--report-->",
+            match user {
+                Some(_) => render_report(post_id, &content.post),
+                None => String::new(),
+            }
+            .as_str(),
+        )
+        // Also the value of the ban form's username field
+        .replace(
+            "<!--author-->",
+            &html_escape::encode_double_quoted_attribute(author_username),
+        )
+        .replace(
+            "<!--createdAt-->",
+            render_time(content.post.created_at, now).as_str(),
+        )
+        .replace(
+            "<!--updatedAt-->",
+            render_edited(content.post.updated_at, now).as_str(),
+        )
+        .replace("<!--score-->", render_score(content.post.score).as_str())
         .replace("<!--replies-->", replies_html.as_str())
+        .replace(
+            "<!--pagination-->",
+            render_pagination(post_id, options, next_cursor.as_deref()).as_str(),
+        )
         .replace("<!--backPath-->", prev_post_id);
 
     let login_regex = Regex::new(r"<!--startLogin-->(.|\n)*<!--endLogin-->").unwrap();
     let logout_regex = Regex::new(r"<!--startLogout-->(.|\n)*<!--endLogout-->").unwrap();
     let post_regex = Regex::new(r"<!--createPostUIStart-->(.|\n)*<!--createPostUIEnd-->").unwrap();
     let edit_regex = Regex::new(r"<!--editPostUIStart-->(.|\n)*<!--editPostUIEnd-->").unwrap();
+    let purge_regex = Regex::new(r"<!--purgeUIStart-->(.|\n)*<!--purgeUIEnd-->").unwrap();
+    let vote_regex = Regex::new(r"<!--voteUIStart-->(.|\n)*<!--voteUIEnd-->").unwrap();
+    let moderate_regex = Regex::new(r"<!--moderateUIStart-->(.|\n)*<!--moderateUIEnd-->").unwrap();
+    let lock_regex = Regex::new(r"<!--lockUIStart-->(.|\n)*<!--lockUIEnd-->").unwrap();
+    let ban_regex = Regex::new(r"<!--banUIStart-->(.|\n)*<!--banUIEnd-->").unwrap();
+    // Only posts without replies can be removed completely
+    if content.post.reply_count > 0 {
+        response = purge_regex.replace_all(&response, "").into_owned();
+    }
+    if lock.is_some() {
+        response = post_regex.replace_all(&response, "").into_owned();
+    }
+    response = match can_moderate {
+        true => render_moderation(&response, &content.post, post_id, lock.as_deref()),
+        false => moderate_regex.replace_all(&response, "").into_owned(),
+    };
+    // Lock forms further down the thread would do nothing, it has to be unlocked where it was locked
+    if matches!(&lock, Some(locked_id) if locked_id != post_id) {
+        response = lock_regex.replace_all(&response, "").into_owned();
+    }
+    // Authors that no longer exist, or the moderator themselves, can't be banned
+    if content.user.is_none() || matches!(&user, Some(user) if user.user_id == author_userid) {
+        response = ban_regex.replace_all(&response, "").into_owned();
+    }
     response = match user {
         Some(user) => {
-            response = response.replace("<!--username-->", user.account.username.as_str());
+            response = response.replace(
+                "<!--username-->",
+                &html_escape::encode_text(&user.account.username),
+            );
             response = login_regex.replace_all(&response, "").into_owned();
-            if user.user_id != author_userid {
+            response = match content.post.deleted || content.post.removed {
+                true => vote_regex.replace_all(&response, "").into_owned(),
+                false => render_vote(&response, get_vote(env, post_id, &user.user_id).await?),
+            };
+            if user.user_id != author_userid || content.post.removed {
                 response = edit_regex.replace_all(&response, "").into_owned();
             }
             response
@@ -88,17 +270,856 @@ pub async fn render_page(
         None => {
             response = response.replace("<!--username-->", "");
             response = logout_regex.replace_all(&response, "").into_owned();
+            response = vote_regex.replace_all(&response, "").into_owned();
             response = edit_regex.replace_all(&response, "").into_owned();
             post_regex.replace_all(&response, "").into_owned()
         }
     };
 
-    let html = match is_login_error {
-        true => response.replace(
+    let html = match login_error {
+        Some(message) => response.replace(
             "<!--loginError-->",
-            include_str!("html/templates/login-error.html"),
+            include_str!("html/templates/login-error.html")
+                .replace("<!--message-->", message)
+                .as_str(),
         ),
-        false => response.replace("<!--loginError-->", ""),
+        None => response.replace("<!--loginError-->", ""),
+    };
+    HttpResponse::from_html(html)
+}
+
+/*
+ * Render replies as nested threads, replies without children render flat
+ * */
+fn render_replies(replies: &[post_obj::ReplyTree], now: u64, can_report: bool) -> String {
+    replies
+        .iter()
+        .map(|reply| {
+            let post = &reply.post;
+            let reply_html = include_str!("html/templates/post.html")
+                .replace("<!--title-->", post.title.as_str())
+                .replace("<!--content-->", render_content(&post.post))
+                .replace(
+                    "<!--createdAt-->",
+                    render_time(post.post.created_at, now).as_str(),
+                )
+                .replace("<!--score-->", render_score(post.post.score).as_str())
+                .replace(
+                    "<!--replyCount-->",
+                    render_reply_count(post.post.reply_count).as_str(),
+                )
+                .replace(
+                    "<!--report-->",
+                    match can_report {
+                        true => render_report(&post.title, &post.post),
+                        false => String::new(),
+                    }
+                    .as_str(),
+                );
+            let user_text = match &post.user {
+                None => "[DELETED]",
+                Some(user) => user.account.username.as_str(),
+            };
+            let reply_html =
+                reply_html.replace("<!--user-->", &html_escape::encode_text(user_text));
+
+            if reply.replies.is_empty() {
+                reply_html
+            } else {
+                format!(
+                    "{}<div class=\"subpost-children\">{}</div>",
+                    reply_html,
+                    render_replies(&reply.replies, now, can_report)
+                )
+            }
+        })
+        .collect()
+}
+
+/*
+ * Links to the previous and next pages of replies, previous goes back to the last cursor in the history
+ * */
+fn render_pagination(post_id: &str, options: &ViewOptions, next_cursor: Option<&str>) -> String {
+    let mut links = Vec::new();
+    if let Some((prev_cursor, history)) = options.history.split_last() {
+        let prev_cursor = Some(prev_cursor.as_str()).filter(|cursor| !cursor.is_empty());
+        links.push(format!(
+            "<a class=\"prev\" href=\"/{}?{}\">previous</a>",
+            post_id,
+            options.page_query(prev_cursor, history)
+        ));
+    }
+    if let Some(next_cursor) = next_cursor {
+        let mut history = options.history.clone();
+        history.push(options.cursor.clone().unwrap_or_default());
+        links.push(format!(
+            "<a class=\"next\" href=\"/{}?{}\">next</a>",
+            post_id,
+            options.page_query(Some(next_cursor), &history)
+        ));
+    }
+    match links.is_empty() {
+        true => String::new(),
+        false => format!("<nav class=\"pagination\">{}</nav>", links.join(" ")),
+    }
+}
+
+fn render_content(post: &post_obj::Post) -> &str {
+    match (post.deleted, post.removed) {
+        (true, _) => "<em class=\"deleted\">[deleted]</em>",
+        (false, true) => "<em class=\"removed\">[removed]</em>",
+        (false, false) => post.content.as_str(),
+    }
+}
+
+/*
+ * Moderators can still read removed posts, so that they can decide whether to restore them
+ * */
+fn render_post_content(post: &post_obj::Post, can_moderate: bool) -> String {
+    match post.removed && !post.deleted && can_moderate {
+        true => format!("{} {}", render_content(post), post.content),
+        false => render_content(post).to_string(),
+    }
+}
+
+/*
+ * Form for reporting a post to the moderators, nothing for posts that are already gone
+ * */
+fn render_report(post_id: &str, post: &post_obj::Post) -> String {
+    if post.deleted || post.removed {
+        return String::new();
+    }
+    let reasons = REPORT_REASONS
+        .iter()
+        .map(|reason| format!("<option value=\"{}\">{}</option>", reason, reason))
+        .collect::<String>();
+    include_str!("html/templates/report.html")
+        .replace("<!--title-->", post_id)
+        .replace("<!--reasons-->", reasons.as_str())
+}
+
+/*
+ * Marker for posts that can't be replied to, linking to where the thread was locked
+ * */
+fn render_lock(lock: Option<&str>) -> String {
+    match lock {
+        Some(locked_id) => format!("<a class=\"locked\" href=\"/{}\">locked</a>", locked_id),
+        None => String::new(),
+    }
+}
+
+/*
+ * Fill in the moderator's forms with whichever of remove/restore and lock/unlock applies
+ * */
+fn render_moderation(
+    html: &str,
+    post: &post_obj::Post,
+    post_id: &str,
+    lock: Option<&str>,
+) -> String {
+    let (remove_action, remove_label) = match post.removed {
+        true => ("restore", "restore"),
+        false => ("delete", "remove"),
+    };
+    let lock_action = match lock {
+        Some(locked_id) if locked_id == post_id => "unlock",
+        _ => "lock",
+    };
+    html.replace("<!--removeAction-->", remove_action)
+        .replace("<!--removeLabel-->", remove_label)
+        .replace("<!--lockAction-->", lock_action)
+}
+
+/*
+ * <time> element showing how long ago something happened, empty for posts from before timestamps
+ * */
+fn render_time(timestamp: Option<u64>, now: u64) -> String {
+    match timestamp {
+        Some(timestamp) => format!(
+            "<time title=\"{}\">{}</time>",
+            utils::format_timestamp(timestamp),
+            utils::format_relative_time(timestamp, now)
+        ),
+        None => String::new(),
+    }
+}
+
+fn render_edited(timestamp: Option<u64>, now: u64) -> String {
+    match timestamp {
+        Some(_) => format!(
+            "<span class=\"edited\">edited {}</span>",
+            render_time(timestamp, now)
+        ),
+        None => String::new(),
+    }
+}
+
+fn render_reply_count(count: u32) -> String {
+    match count {
+        1 => "1 reply".to_string(),
+        count => format!("{} replies", count),
+    }
+}
+
+fn render_score(score: i64) -> String {
+    match score {
+        1 | -1 => format!("{} point", score),
+        score => format!("{} points", score),
+    }
+}
+
+/*
+ * Fill in the vote buttons, pressing the button for the current vote again removes it
+ * */
+fn render_vote(html: &str, vote: i8) -> String {
+    let (up_class, up_value) = match vote {
+        1 => ("voted", "0"),
+        _ => ("", "1"),
+    };
+    let (down_class, down_value) = match vote {
+        -1 => ("voted", "0"),
+        _ => ("", "-1"),
+    };
+    html.replace("<!--upvoteClass-->", up_class)
+        .replace("<!--upvoteValue-->", up_value)
+        .replace("<!--downvoteClass-->", down_class)
+        .replace("<!--downvoteValue-->", down_value)
+}
+
+/*
+ * Shown to a banned user instead of logging them in
+ * */
+pub fn render_banned(ban: &user_obj::Ban) -> Result<HttpResponse> {
+    let reason = match &ban.reason {
+        Some(reason) => format!(
+            " The reason given was: {}",
+            html_escape::encode_text(reason)
+        ),
+        None => String::new(),
+    };
+    Ok(render_message(
+        "Account banned",
+        &format!(
+            "This account has been banned {}.{}",
+            render_ban_end(ban.until),
+            reason
+        ),
+    )?
+    .with_status(403))
+}
+
+fn render_ban_end(until: Option<u64>) -> String {
+    match until {
+        Some(until) => format!("until {}", utils::format_timestamp(until)),
+        None => "permanently".to_string(),
+    }
+}
+
+/*
+ * The moderators' queue of reported posts, most reported first
+ * */
+pub async fn render_reports<E: Backend>(env: &E) -> Result<HttpResponse> {
+    let styles = [
+        include_str!("html/style/layout.css"),
+        include_str!("html/style/index.css"),
+    ];
+
+    let mut reports_html = String::new();
+    for post_id in list_reported(env, REPORT_QUEUE_SIZE).await? {
+        let (content, reports) = match (
+            get_content(env, &post_id).await?,
+            get_reports(env, &post_id).await?,
+        ) {
+            (Some(content), Some(reports)) => (content, reports),
+            _ => continue,
+        };
+        let reasons = reports
+            .reason_counts()
+            .iter()
+            .map(|(reason, count)| format!("{} ({})", reason, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let count = match reports.reports.len() {
+            1 => "1 report".to_string(),
+            count => format!("{} reports", count),
+        };
+        let hidden = match reports.hidden {
+            true => "<em class=\"removed\">hidden automatically</em>",
+            false => "",
+        };
+        let author = match &content.user {
+            Some(user) => user.account.username.as_str(),
+            None => "[Deleted]",
+        };
+        reports_html.push_str(
+            &include_str!("html/templates/reported-post.html")
+                .replace("<!--title-->", &post_id)
+                .replace("<!--author-->", &html_escape::encode_text(author))
+                .replace("<!--count-->", count.as_str())
+                .replace("<!--reasons-->", reasons.as_str())
+                .replace("<!--hidden-->", hidden)
+                .replace(
+                    "<!--content-->",
+                    render_post_content(&content.post, true).as_str(),
+                ),
+        );
+    }
+    if reports_html.is_empty() {
+        reports_html = "<li>There are no open reports</li>".to_string();
+    }
+
+    let html = include_str!("html/reports.html")
+        .replace("/*style*/", styles.join("\n").as_str())
+        .replace("<!--reports-->", reports_html.as_str());
+    HttpResponse::from_html(html)
+}
+
+/*
+ * A page with just a short message, e.g. after following a link sent by email.
+ * The message is inserted as is, so must already be escaped
+ * */
+pub fn render_message(title: &str, message: &str) -> Result<HttpResponse> {
+    let styles = [
+        include_str!("html/style/layout.css"),
+        include_str!("html/style/index.css"),
+    ];
+
+    let html = include_str!("html/message.html")
+        .replace("/*style*/", styles.join("\n").as_str())
+        .replace("<!--title-->", title)
+        .replace("<!--message-->", message);
+    HttpResponse::from_html(html)
+}
+
+/*
+ * Activate the account for a verification link
+ * */
+pub async fn render_verification<E: Backend>(env: &E, token: &str) -> Result<HttpResponse> {
+    match verify_user(env, token).await? {
+        Some(_) => render_message(
+            "Account verified",
+            "Your email address has been verified, you can now log in.",
+        ),
+        None => Ok(render_message(
+            "Invalid link",
+            "This verification link is invalid or has expired, try registering again.",
+        )?
+        .with_status(400)),
+    }
+}
+
+/*
+ * Account settings, along with every session the user is logged in with and an error from the
+ * last change if it failed
+ * */
+pub async fn render_account<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    session_id: &str,
+    error: Option<&str>,
+) -> Result<HttpResponse> {
+    let styles = [
+        include_str!("html/style/layout.css"),
+        include_str!("html/style/index.css"),
+    ];
+
+    let error_html = match error {
+        Some(message) => {
+            include_str!("html/templates/login-error.html").replace("<!--message-->", message)
+        }
+        None => String::new(),
+    };
+    let html = include_str!("html/account.html")
+        .replace("/*style*/", styles.join("\n").as_str())
+        .replace("<!--accountError-->", error_html.as_str())
+        .replace(
+            "<!--username-->",
+            &html_escape::encode_double_quoted_attribute(&user.account.username),
+        )
+        .replace(
+            "<!--sessions-->",
+            render_sessions(env, user, session_id).await?.as_str(),
+        )
+        .replace(
+            "<!--role-->",
+            match user.account.role {
+                user_obj::Role::User => String::new(),
+                role => format!(" <span class=\"role\">{}</span>", role.name()),
+            }
+            .as_str(),
+        );
+    let html = match user.account.role {
+        user_obj::Role::Admin => html,
+        _ => Regex::new(r"<!--adminUIStart-->(.|\n)*<!--adminUIEnd-->")
+            .unwrap()
+            .replace_all(&html, "")
+            .into_owned(),
+    };
+    let html = match user.can_moderate() {
+        true => html,
+        false => Regex::new(r"<!--moderatorUIStart-->(.|\n)*<!--moderatorUIEnd-->")
+            .unwrap()
+            .replace_all(&html, "")
+            .into_owned(),
+    };
+    HttpResponse::from_html(html)
+}
+
+async fn render_sessions<E: Backend>(
+    env: &E,
+    user: &user_obj::User,
+    session_id: &str,
+) -> Result<String> {
+    let now = env.now();
+    Ok(list_sessions(env, &user.user_id)
+        .await?
+        .iter()
+        .map(|session| {
+            let revoke = match session.session_id == session_id {
+                true => "<em>this device</em>".to_string(),
+                false => format!(
+                    "<form method=\"POST\" action=\"/?revoke\">\
+                     <input type=\"hidden\" name=\"session\" value=\"{}\">\
+                     <button type=\"submit\">Log out</button></form>",
+                    crypto_helpers::public_id(&session.session_id)
+                ),
+            };
+            let client = &session.info.client;
+            include_str!("html/templates/session.html")
+                .replace(
+                    "<!--device-->",
+                    &html_escape::encode_text(
+                        client.user_agent.as_deref().unwrap_or("Unknown device"),
+                    ),
+                )
+                .replace(
+                    "<!--region-->",
+                    &html_escape::encode_text(client.region.as_deref().unwrap_or("")),
+                )
+                .replace(
+                    "<!--createdAt-->",
+                    match session.info.created_at {
+                        Some(_) => render_time(session.info.created_at, now),
+                        None => "some time ago".to_string(),
+                    }
+                    .as_str(),
+                )
+                .replace(
+                    "<!--lastSeen-->",
+                    render_time(Some(session.info.last_seen), now).as_str(),
+                )
+                .replace("<!--revoke-->", revoke.as_str())
+        })
+        .collect())
+}
+
+/*
+ * The audit log filter from ?actor= ?action= and ?target=, with the actor given by username.
+ * None if there is no such user, as then nothing can match
+ * */
+pub async fn audit_filter_from_query<E: Backend>(
+    env: &E,
+    query: &HashMap<String, String>,
+) -> Result<Option<audit_obj::AuditFilter>> {
+    let field = |name: &str| {
+        query
+            .get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
     };
-    Response::from_html(html)
+    let actor = match field("actor") {
+        Some(username) => match get_user_by_username(env, &username).await? {
+            Some(user) => Some(user.user_id),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+    Ok(Some(audit_obj::AuditFilter {
+        actor,
+        action: field("action"),
+        target: field("target"),
+    }))
+}
+
+/*
+ * A page of the audit log for admins, filtered by the query
+ * */
+pub async fn render_audit<E: Backend>(
+    env: &E,
+    query: &HashMap<String, String>,
+) -> Result<HttpResponse> {
+    let styles = [
+        include_str!("html/style/layout.css"),
+        include_str!("html/style/index.css"),
+    ];
+    let cursor = query
+        .get("cursor")
+        .map(|cursor| cursor.as_str())
+        .filter(|cursor| !cursor.is_empty());
+    let (entries, next_cursor) = match audit_filter_from_query(env, query).await? {
+        Some(filter) => audit::list(env, &filter, cursor, AUDIT_PAGE_SIZE).await?,
+        None => (Vec::new(), None),
+    };
+
+    // Usernames are looked up once each, most pages are a handful of moderators
+    let mut usernames: HashMap<String, String> = HashMap::new();
+    let mut entries_html = String::new();
+    for entry in &entries {
+        let actor = render_audit_user(env, &mut usernames, &entry.actor).await?;
+        let target = match entry.action.targets_post() {
+            true => format!("<a href=\"/{}\">/{}</a>", entry.target, entry.target),
+            false => render_audit_user(env, &mut usernames, &entry.target).await?,
+        };
+        let action = match entry.action {
+            audit_obj::AuditAction::ChangeRole(role) => format!("made {}", role.name()),
+            audit_obj::AuditAction::BanUser(until) => format!("banned {}", render_ban_end(until)),
+            action => action.name().to_string(),
+        };
+        let reason = match &entry.reason {
+            Some(reason) => format!(
+                "<q class=\"reason\">{}</q>",
+                html_escape::encode_text(reason)
+            ),
+            None => String::new(),
+        };
+        entries_html.push_str(
+            &include_str!("html/templates/audit-entry.html")
+                .replace(
+                    "<!--timestamp-->",
+                    render_time(Some(entry.timestamp), env.now()).as_str(),
+                )
+                .replace("<!--actor-->", actor.as_str())
+                .replace("<!--action-->", action.as_str())
+                .replace("<!--target-->", target.as_str())
+                .replace("<!--reason-->", reason.as_str()),
+        );
+    }
+    if entries.is_empty() {
+        entries_html = "<li>Nothing has been recorded</li>".to_string();
+    }
+
+    let value = |name: &str| {
+        html_escape::encode_double_quoted_attribute(query.get(name).map_or("", |value| value))
+            .to_string()
+    };
+    let actions = audit_obj::AuditAction::NAMES
+        .iter()
+        .map(|name| {
+            let selected = match query.get("action") {
+                Some(action) if action == name => " selected",
+                _ => "",
+            };
+            format!("<option value=\"{}\"{}>{}</option>", name, selected, name)
+        })
+        .collect::<String>();
+    let pagination = match next_cursor {
+        Some(next_cursor) => {
+            let mut next = url::form_urlencoded::Serializer::new(String::new());
+            next.append_pair("audit", "");
+            for name in &["actor", "action", "target"] {
+                if let Some(value) = query.get(*name) {
+                    next.append_pair(name, value);
+                }
+            }
+            next.append_pair("cursor", &next_cursor);
+            format!(
+                "<nav class=\"pagination\"><a class=\"next\" href=\"/?{}\">next</a></nav>",
+                html_escape::encode_double_quoted_attribute(&next.finish())
+            )
+        }
+        None => String::new(),
+    };
+
+    let html = include_str!("html/audit.html")
+        .replace("/*style*/", styles.join("\n").as_str())
+        .replace("<!--actor-->", value("actor").as_str())
+        .replace("<!--target-->", value("target").as_str())
+        .replace("<!--actions-->", actions.as_str())
+        .replace("<!--entries-->", entries_html.as_str())
+        .replace("<!--pagination-->", pagination.as_str());
+    HttpResponse::from_html(html)
+}
+
+/*
+ * Username linking to the entries they made, or [deleted] for users that no longer exist
+ * */
+async fn render_audit_user<E: Backend>(
+    env: &E,
+    usernames: &mut HashMap<String, String>,
+    user_id: &str,
+) -> Result<String> {
+    if !usernames.contains_key(user_id) {
+        let username = match get_user(env, user_id).await? {
+            Some(user) => format!(
+                "<a href=\"/?audit&amp;actor={}\">{}</a>",
+                url::form_urlencoded::byte_serialize(user.account.username.as_bytes())
+                    .collect::<String>(),
+                html_escape::encode_text(&user.account.username)
+            ),
+            None => "[deleted]".to_string(),
+        };
+        usernames.insert(user_id.to_string(), username);
+    }
+    Ok(usernames[user_id].to_string())
+}
+
+pub fn render_forgot_password() -> Result<HttpResponse> {
+    render_message(
+        "Forgot password",
+        include_str!("html/templates/forgot-form.html"),
+    )
+}
+
+/*
+ * Form for choosing a new password, the token from the emailed link is submitted with it
+ * */
+pub fn render_reset_password(token: &str) -> Result<HttpResponse> {
+    render_message(
+        "Reset password",
+        include_str!("html/templates/reset-form.html")
+            .replace(
+                "<!--token-->",
+                &html_escape::encode_double_quoted_attribute(token),
+            )
+            .as_str(),
+    )
+}
+
+/*
+ * Render the current content of a post followed by its previous revisions, newest first
+ * */
+pub async fn render_history<E: Backend>(path: &str, env: &E) -> Result<HttpResponse> {
+    let styles = [
+        include_str!("html/style/layout.css"),
+        include_str!("html/style/index.css"),
+    ];
+
+    let style = styles.join("\n");
+
+    // Get post id from path
+    let post_id = path
+        .strip_prefix('/')
+        .expect("Expected path to begin with /");
+
+    let content = match get_content(env, post_id).await? {
+        None => {
+            return HttpResponse::error("Page Not Found", 404);
+        }
+        Some(content) => content,
+    };
+
+    // Removed posts would otherwise still be readable through their history
+    let revisions = match content.post.removed {
+        true => Vec::new(),
+        false => get_revisions(env, post_id).await?,
+    };
+    let revisions_html = revisions
+        .iter()
+        .rev()
+        .map(|revision| {
+            include_str!("html/templates/revision.html")
+                .replace(
+                    "<!--timestamp-->",
+                    utils::format_timestamp(revision.timestamp).as_str(),
+                )
+                .replace("<!--content-->", revision.content.as_str())
+        })
+        .collect::<String>();
+
+    let author_username = match &content.user {
+        Some(user) => &user.account.username,
+        None => "[Deleted]",
+    };
+
+    let html = include_str!("html/history.html")
+        .replace("/*style*/", style.as_str())
+        .replace("<!--title-->", post_id)
+        .replace("<!--content-->", render_content(&content.post))
+        .replace("<!--author-->", &html_escape::encode_text(author_username))
+        .replace("<!--revisions-->", revisions_html.as_str());
+    HttpResponse::from_html(html)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use crate::db::post::{delete_post, edit_post, post_content};
+    use crate::db::user::get_session;
+    use crate::harness::{register, TestRequest};
+    use futures::executor::block_on;
+
+    #[test]
+    fn missing_page_is_not_found() {
+        let env = MemoryBackend::new();
+        let response = TestRequest::get("/a").send(&env);
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn page_shows_post_and_replies() {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        for (post_id, content) in &[("", "root post"), ("a", "first reply")] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, content, user)).unwrap();
+        }
+
+        let response = TestRequest::get("/").send(&env);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("root post"));
+        assert!(response.body.contains("first reply"));
+        assert!(response.body.contains("alice"));
+        // Logged out users get the login form but not the reply form
+        assert!(response.body.contains("class=\"login\""));
+        assert!(!response.body.contains("class=\"user-subpost\""));
+
+        let response = TestRequest::get("/")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert!(!response.body.contains("class=\"login\""));
+        assert!(response.body.contains("class=\"user-subpost\""));
+        assert!(response.body.contains("class=\"delete\""));
+    }
+
+    #[test]
+    fn history_lists_revisions() {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+        block_on(post_content(&env, "", "original", user)).unwrap();
+        block_on(edit_post(&env, "", "edited")).unwrap();
+
+        let response = TestRequest::get("/").query("history").send(&env);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("original"));
+        assert!(response.body.contains("edited"));
+        assert!(response.body.contains("UTC"));
+
+        let response = TestRequest::get("/a").query("history").send(&env);
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn tombstones_keep_replies_visible() {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        for (post_id, content) in &[("", "root"), ("a", "secret"), ("ab", "still here")] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, content, user)).unwrap();
+        }
+        block_on(delete_post(&env, "a")).unwrap();
+
+        let response = TestRequest::get("/a")
+            .cookie("sessionId", &session_id)
+            .send(&env);
+        assert_eq!(response.status, 200);
+        assert!(!response.body.contains("secret"));
+        assert!(response.body.contains("[deleted]"));
+        assert!(response.body.contains("still here"));
+        assert!(!response.body.contains("class=\"edit\""));
+
+        let response = TestRequest::get("/").send(&env);
+        assert!(response.body.contains("[deleted]"));
+    }
+
+    #[test]
+    fn depth_renders_nested_threads() {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        for (post_id, content) in &[("", "root"), ("a", "child"), ("ab", "grandchild")] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, content, user)).unwrap();
+        }
+
+        let response = TestRequest::get("/").send(&env);
+        assert!(response.body.contains("child"));
+        assert!(!response.body.contains("grandchild"));
+        assert!(!response.body.contains("class=\"subpost-children\""));
+
+        let response = TestRequest::get("/").query_value("depth", "2").send(&env);
+        assert!(response.body.contains("grandchild"));
+        assert!(response.body.contains("class=\"subpost-children\""));
+
+        // Invalid depths fall back to the default
+        let response = TestRequest::get("/").query_value("depth", "x").send(&env);
+        assert!(!response.body.contains("grandchild"));
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let mut query = HashMap::new();
+        query.insert("depth".to_string(), "1000".to_string());
+        assert_eq!(ViewOptions::from_query(&query).depth, MAX_DEPTH);
+        query.insert("depth".to_string(), "0".to_string());
+        assert_eq!(ViewOptions::from_query(&query).depth, 1);
+    }
+
+    #[test]
+    fn replies_are_paginated() {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        for (post_id, content) in &[("", "root"), ("a", "first"), ("b", "second")] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, content, user)).unwrap();
+        }
+
+        let response = TestRequest::get("/").query_value("limit", "1").send(&env);
+        assert!(response.body.contains("first"));
+        assert!(!response.body.contains("second"));
+        assert!(!response.body.contains("class=\"prev\""));
+        let next = link_query(&response.body, "next");
+
+        let mut request = TestRequest::get("/");
+        for (name, value) in url::form_urlencoded::parse(next.as_bytes()) {
+            request = request.query_value(&name, &value);
+        }
+        let response = request.send(&env);
+        assert!(!response.body.contains("first"));
+        assert!(response.body.contains("second"));
+        assert!(!response.body.contains("class=\"next\""));
+        // The previous link leads back to the first page
+        assert_eq!(link_query(&response.body, "prev"), "limit=1");
+    }
+
+    fn link_query(body: &str, class: &str) -> String {
+        let start = format!("<a class=\"{}\" href=\"/?", class);
+        let query = &body[body.find(&start).unwrap() + start.len()..];
+        query[..query.find('"').unwrap()].to_string()
+    }
+
+    #[test]
+    fn page_size_is_capped() {
+        let mut query = HashMap::new();
+        query.insert("limit".to_string(), "1000".to_string());
+        assert_eq!(ViewOptions::from_query(&query).limit, MAX_PAGE_SIZE);
+        query.insert("limit".to_string(), "x".to_string());
+        assert_eq!(ViewOptions::from_query(&query).limit, DEFAULT_PAGE_SIZE);
+    }
+
+    #[test]
+    fn sort_orders_replies() {
+        let env = MemoryBackend::new();
+        let session_id = register(&env, "a@example.com", "alice");
+        for (post_id, content) in &[("", "root"), ("b", "older"), ("a", "newer")] {
+            let user = block_on(get_session(&env, &session_id)).unwrap().unwrap();
+            block_on(post_content(&env, post_id, content, user)).unwrap();
+            env.advance(1000);
+        }
+
+        let response = TestRequest::get("/").send(&env);
+        assert!(response.body.find("newer") < response.body.find("older"));
+        let response = TestRequest::get("/")
+            .query_value("sort", "oldest")
+            .send(&env);
+        assert!(response.body.find("older") < response.body.find("newer"));
+
+        // Pagination links keep the sort order
+        let response = TestRequest::get("/")
+            .query_value("sort", "oldest")
+            .query_value("limit", "1")
+            .send(&env);
+        assert!(response.body.contains("?sort=oldest&limit=1&cursor=1"));
+    }
 }
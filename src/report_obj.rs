@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+/*
+ * Reasons a reader can give when reporting a post
+ * */
+pub const REPORT_REASONS: [&str; 4] = ["spam", "abuse", "off-topic", "other"];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Report {
+    pub user: String,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+/*
+ * Every open report on a post, at most one from each user
+ * */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PostReports {
+    pub reports: Vec<Report>,
+    /*
+     * Whether the reports hid the post, in which case dismissing them shows it again
+     * */
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+impl PostReports {
+    /*
+     * How many times each reason was given, most common first. Content filters report with
+     * reasons of their own as well as the ones readers choose from
+     * */
+    pub fn reason_counts(&self) -> Vec<(&str, usize)> {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for report in &self.reports {
+            match counts
+                .iter_mut()
+                .find(|(reason, _)| *reason == report.reason)
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((&report.reason, 1)),
+            }
+        }
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts
+    }
+}
+
+/*
+ * Stored as KV metadata on each post's reports so that the queue can be ordered from the key
+ * listing alone
+ * */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ReportMetadata {
+    pub count: usize,
+    pub latest: u64,
+}
+
+impl From<&PostReports> for ReportMetadata {
+    fn from(reports: &PostReports) -> Self {
+        ReportMetadata {
+            count: reports.reports.len(),
+            latest: reports
+                .reports
+                .iter()
+                .map(|report| report.timestamp)
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reasons_are_counted() {
+        let report = |reason: &str| Report {
+            user: String::new(),
+            reason: reason.to_string(),
+            timestamp: 0,
+        };
+        let reports = PostReports {
+            reports: vec![report("abuse"), report("spam"), report("abuse")],
+            hidden: false,
+        };
+        assert_eq!(reports.reason_counts(), [("abuse", 2), ("spam", 1)]);
+    }
+}
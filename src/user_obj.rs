@@ -1,9 +1,90 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserAccount {
+    /*
+     * Empty for accounts from before user ids, which are stored under their email instead
+     * */
+    #[serde(default)]
+    pub email: String,
     pub hash: String,
     pub username: String,
+    #[serde(default)]
+    pub status: AccountStatus,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub ban: Option<Ban>,
+}
+
+/*
+ * New accounts stay pending until their email address is verified, accounts from before
+ * verification existed are active
+ * */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Pending,
+}
+
+/*
+ * What a user is allowed to do, each role can do everything the ones before it can
+ * */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub enum Role {
+    #[default]
+    User,
+    /*
+     * Can remove, restore and lock anyone's posts
+     * */
+    Moderator,
+    /*
+     * Can also change other users' roles
+     * */
+    Admin,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "user" => Some(Role::User),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/*
+ * Stops an account logging in or posting, until it expires for a suspension
+ * */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    /*
+     * None for a permanent ban
+     * */
+    #[serde(default)]
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: u64) -> bool {
+        match self.until {
+            Some(until) => now < until,
+            None => true,
+        }
+    }
 }
 
 pub struct User {
@@ -11,20 +92,106 @@ pub struct User {
     pub user_id: String,
 }
 
+impl User {
+    pub fn can_moderate(&self) -> bool {
+        self.account.role >= Role::Moderator
+    }
+
+    /*
+     * The ban stopping this user, None once a suspension is over
+     * */
+    pub fn active_ban(&self, now: u64) -> Option<&Ban> {
+        self.account.ban.as_ref().filter(|ban| ban.is_active(now))
+    }
+}
+
+/*
+ * The device a session was started from
+ * */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Client {
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    /*
+     * Used to limit login attempts, not kept with the session
+     * */
+    #[serde(skip)]
+    pub ip: Option<String>,
+}
+
+/*
+ * Kept in the user's session index so that they can see where they are logged in
+ * */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionInfo {
+    /*
+     * None for sessions started before this was recorded
+     * */
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub last_seen: u64,
+    #[serde(flatten)]
+    pub client: Client,
+}
+
+pub struct Session {
+    pub session_id: String,
+    pub info: SessionInfo,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use serde_json;
 
     #[test]
     fn serialize_obj() {
         let hash = "1";
         let acc = UserAccount {
+            email: "test@example.com".to_string(),
             hash: hash.to_string(),
             username: "test".to_string(),
+            status: AccountStatus::Active,
+            role: Role::User,
+            ban: None,
         };
         let serialized = serde_json::to_string(&acc).unwrap();
         println!("{}", serialized);
         assert_ne!(serialized, "");
     }
+
+    #[test]
+    fn legacy_accounts_are_active() {
+        let acc: UserAccount = serde_json::from_str(r#"{"hash":"1","username":"test"}"#).unwrap();
+        assert_eq!(acc.status, AccountStatus::Active);
+        assert_eq!(acc.email, "");
+        assert_eq!(acc.role, Role::User);
+        assert_eq!(acc.ban, None);
+    }
+
+    #[test]
+    fn suspensions_expire() {
+        let suspension = Ban {
+            until: Some(10),
+            reason: None,
+        };
+        assert!(suspension.is_active(9));
+        assert!(!suspension.is_active(10));
+        let ban = Ban {
+            until: None,
+            reason: None,
+        };
+        assert!(ban.is_active(u64::MAX));
+    }
+
+    #[test]
+    fn role_names() {
+        for role in &[Role::User, Role::Moderator, Role::Admin] {
+            assert_eq!(Role::from_name(role.name()), Some(*role));
+        }
+        assert_eq!(Role::from_name("owner"), None);
+        assert!(Role::Admin > Role::Moderator && Role::Moderator > Role::User);
+    }
 }
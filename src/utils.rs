@@ -12,6 +12,19 @@ cfg_if! {
     }
 }
 
+cfg_if! {
+    // The worker log is only there when running as a worker, not in tests
+    if #[cfg(target_arch = "wasm32")] {
+        pub fn log(message: &str) {
+            console_log!("{}", message);
+        }
+    } else {
+        pub fn log(message: &str) {
+            eprintln!("{}", message);
+        }
+    }
+}
+
 pub fn log_request(req: &Request) {
     console_log!(
         "{} - [{}], located at: {:?}, within: {}",
@@ -23,3 +36,83 @@ pub fn log_request(req: &Request) {
             .unwrap_or_else(|| { "unknown region".into() })
     );
 }
+
+/*
+ * Format a unix timestamp in milliseconds as e.g. 2021-09-02 19:38 UTC
+ * */
+pub fn format_timestamp(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let days = (seconds / 86400) as i64;
+    let minutes = (seconds % 86400) / 60;
+
+    // Convert days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/*
+ * Describe how long ago a timestamp was, e.g. 5 minutes ago
+ * */
+pub fn format_relative_time(millis: u64, now: u64) -> String {
+    let seconds = now.saturating_sub(millis) / 1000;
+    let (count, unit) = match seconds {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        86400..=2591999 => (seconds / 86400, "day"),
+        2592000..=31535999 => (seconds / 2592000, "month"),
+        _ => (seconds / 31536000, "year"),
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(1630611511000), "2021-09-02 19:38 UTC");
+        assert_eq!(format_timestamp(951782400000), "2000-02-29 00:00 UTC");
+    }
+
+    #[test]
+    fn format_relative_times() {
+        let now = 1630611511000;
+        assert_eq!(format_relative_time(now, now), "just now");
+        assert_eq!(format_relative_time(now + 5000, now), "just now");
+        assert_eq!(format_relative_time(now - 60_000, now), "1 minute ago");
+        assert_eq!(format_relative_time(now - 7_200_000, now), "2 hours ago");
+        assert_eq!(
+            format_relative_time(now - 86_400_000 * 3, now),
+            "3 days ago"
+        );
+        assert_eq!(
+            format_relative_time(now - 86_400_000 * 400, now),
+            "1 year ago"
+        );
+    }
+}